@@ -0,0 +1,128 @@
+use std::fmt;
+
+use crate::tokenizer::Span;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    // the whole source line the error is on and how many characters of it the caret covers.
+    pub excerpt: String,
+    pub width: usize,
+    // file:line of every .include that led to the file, innermost first.
+    pub included_from: Vec<String>,
+}
+
+pub struct Source {
+    pub name: String,
+    pub text: String,
+    // the .include that pulled the file in, none for the files handed to the assembler.
+    pub included_from: Option<Span>,
+}
+
+impl Source {
+    fn line(&self, offset: usize) -> usize {
+        self.text[..offset].matches('\n').count() + 1
+    }
+}
+
+// every file read while assembling, spans say which one they point into.
+#[derive(Default)]
+pub struct SourceMap {
+    sources: Vec<Source>,
+}
+
+impl SourceMap {
+    pub fn add(&mut self, source: Source) -> usize {
+        self.sources.push(source);
+        self.sources.len() - 1
+    }
+
+    pub fn get(&self, id: usize) -> &Source {
+        &self.sources[id]
+    }
+
+    // where a file was first read, so its errors can be listed in reading order.
+    pub fn position(&self, name: &str) -> usize {
+        self.sources.iter().position(|source| source.name == name).unwrap_or(self.sources.len())
+    }
+
+    pub fn text(&self, span: Span) -> &str {
+        &self.get(span.source).text[span.start..span.end]
+    }
+
+    pub fn error(&self, span: Span, message: impl Into<String>) -> Diagnostic {
+        let source = self.get(span.source);
+        let line_start = source.text[..span.start].rfind('\n').map_or(0, |index| index + 1);
+        let line_end = source.text[span.start..].find('\n').map_or(source.text.len(), |index| span.start + index);
+        let excerpt = source.text[line_start..line_end].trim_end_matches('\r');
+        // a span running over several lines only gets underlined on the first one.
+        let width = source.text[span.start..span.end.clamp(span.start, line_start + excerpt.len())].chars().count();
+        let mut included_from = vec![];
+        let mut parent = source.included_from;
+        while let Some(at) = parent {
+            let including = self.get(at.source);
+            included_from.push(format!("{}:{}", including.name, including.line(at.start)));
+            parent = including.included_from;
+        }
+        Diagnostic {
+            message: message.into(),
+            file: source.name.clone(),
+            line: source.line(span.start),
+            column: source.text[line_start..span.start].chars().count() + 1,
+            excerpt: excerpt.to_string(),
+            width: width.max(1),
+            included_from,
+        }
+    }
+}
+
+// the closest few candidates by edit distance, as an ending for an "unknown name" message.
+pub fn did_you_mean<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> String {
+    let limit = (name.chars().count() / 3).max(1);
+    let mut close: Vec<(usize, &str)> = candidates.into_iter()
+        .filter(|candidate| !candidate.starts_with("__"))
+        .map(|candidate| (edit_distance(&name.to_lowercase(), &candidate.to_lowercase()), candidate))
+        .filter(|(distance, _)| *distance <= limit)
+        .collect();
+    close.sort();
+    let names: Vec<String> = close.iter().take(3).map(|(_, candidate)| format!("`{}`", candidate)).collect();
+    match names.as_slice() {
+        [] => String::new(),
+        [only] => format!(", did you mean {}?", only),
+        [rest @ .., last] => format!(", did you mean {} or {}?", rest.join(", "), last),
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + (a != *b) as usize;
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let gutter = " ".repeat(self.line.to_string().len());
+        // tabs are kept so the caret lines up with the excerpt however the terminal draws them.
+        let indent: String = self.excerpt.chars().take(self.column - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+        writeln!(f, "error: {}", self.message)?;
+        writeln!(f, "{}--> {}:{}:{}", gutter, self.file, self.line, self.column)?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", self.line, self.excerpt)?;
+        write!(f, "{} | {}{}", gutter, indent, "^".repeat(self.width))?;
+        for including in &self.included_from {
+            write!(f, "\n{} = note: included from {}", gutter, including)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,343 @@
+use arsenal_globals::register_index;
+
+use crate::diagnostic::{Diagnostic, SourceMap};
+use crate::tokenizer::{ArsenalToken, Span, Spanned};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOperator {
+    Multiply,
+    Divide,
+    Add,
+    Subtract,
+    ShiftLeft,
+    ShiftRight,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    NotEqual,
+    And,
+    Or,
+}
+
+// loosest binding first, like in c.
+const LEVELS: &[&[BinaryOperator]] = &[
+    &[BinaryOperator::Or],
+    &[BinaryOperator::And],
+    &[BinaryOperator::Equal, BinaryOperator::NotEqual],
+    &[BinaryOperator::Less, BinaryOperator::LessOrEqual, BinaryOperator::Greater, BinaryOperator::GreaterOrEqual],
+    &[BinaryOperator::ShiftLeft, BinaryOperator::ShiftRight],
+    &[BinaryOperator::Add, BinaryOperator::Subtract],
+    &[BinaryOperator::Multiply, BinaryOperator::Divide],
+];
+
+#[derive(Debug, Clone)]
+pub enum Expression {
+    Number(i128),
+    Label(String, Span),
+    Size(String, Span),
+    Constant(String, Span),
+    Negate(Box<Expression>, Span),
+    Not(Box<Expression>),
+    Binary(BinaryOperator, Box<Expression>, Box<Expression>, Span),
+}
+
+struct ExpressionParser<'a> {
+    sources: &'a SourceMap,
+    tokens: &'a [Spanned<ArsenalToken>],
+    position: usize,
+    // `a -1` is tokenized as a and the number -1, this holds the number once its sign was taken as the operator
+    // so it reads the same as `a - 1`.
+    split: Option<Span>,
+}
+
+// reads a whole expression starting at position, it ends at the first token that cannot continue it.
+pub fn parse(sources: &SourceMap, tokens: &[Spanned<ArsenalToken>], position: &mut usize) -> Result<(Expression, Span), Diagnostic> {
+    run(sources, tokens, position, |parser| parser.binary(0))
+}
+
+// reads a single operand, a number, name, reference or parenthesized expression with its unary operators.
+pub fn parse_operand(sources: &SourceMap, tokens: &[Spanned<ArsenalToken>], position: &mut usize) -> Result<(Expression, Span), Diagnostic> {
+    run(sources, tokens, position, |parser| parser.unary())
+}
+
+// the name after &, a label, a local .label, global.label for a local one elsewhere or an anonymous 1f or 1b.
+pub fn label_reference(tokens: &[Spanned<ArsenalToken>], position: &mut usize) -> Option<(String, Span)> {
+    use ArsenalToken::*;
+    let (name, span) = match &tokens.get(*position)?.token {
+        Identifier(name) | SpecialIdentifier(name) | AnonymousLabel(name) => (name.clone(), tokens[*position].span),
+        _ => return None,
+    };
+    *position += 1;
+    if let (Identifier(_), Some(Spanned { token: SpecialIdentifier(local), span: local_span })) = (&tokens[*position - 1].token, tokens.get(*position)) {
+        if local_span.start == span.end && local_span.source == span.source {
+            *position += 1;
+            return Some((name + local, span.to(*local_span)));
+        }
+    }
+    Some((name, span))
+}
+
+fn run(
+    sources: &SourceMap,
+    tokens: &[Spanned<ArsenalToken>],
+    position: &mut usize,
+    read: impl FnOnce(&mut ExpressionParser) -> Result<Expression, Diagnostic>,
+) -> Result<(Expression, Span), Diagnostic> {
+    let start = *position;
+    let mut parser = ExpressionParser { sources, tokens, position: start, split: None };
+    let expression = read(&mut parser);
+    *position = parser.position;
+    let span = tokens.get(start).map_or(Span::default(), |first| first.span.to(tokens[parser.position.max(start + 1) - 1].span));
+    Ok((expression?, span))
+}
+
+impl<'a> ExpressionParser<'a> {
+    fn peek(&self) -> Option<&'a Spanned<ArsenalToken>> {
+        self.tokens.get(self.position)
+    }
+
+    fn unexpected(&self, expected: &str) -> Diagnostic {
+        match self.peek() {
+            Some(found) => self.sources.error(found.span, format!("expected {}, found `{}`", expected, found.token.text())),
+            None => {
+                let end = self.tokens.last().map_or(Span::default(), |last| Span { start: last.span.end, ..last.span });
+                self.sources.error(end, format!("expected {}, found the end of the file", expected))
+            },
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expression, Diagnostic> {
+        let Some(operators) = LEVELS.get(level) else {
+            return self.unary();
+        };
+        let mut left = self.binary(level + 1)?;
+        while let Some((operator, span)) = self.operator(operators) {
+            let right = self.binary(level + 1)?;
+            left = Expression::Binary(operator, Box::new(left), Box::new(right), span);
+        }
+        Ok(left)
+    }
+
+    // takes the next token when it is one of the operators of this level.
+    fn operator(&mut self, operators: &[BinaryOperator]) -> Option<(BinaryOperator, Span)> {
+        use ArsenalToken::*;
+        let token = self.peek()?;
+        let operator = match &token.token {
+            Operator(text) => match text.as_str() {
+                "*" => BinaryOperator::Multiply,
+                "/" => BinaryOperator::Divide,
+                "+" => BinaryOperator::Add,
+                "-" => BinaryOperator::Subtract,
+                "<<" => BinaryOperator::ShiftLeft,
+                ">>" => BinaryOperator::ShiftRight,
+                "<" => BinaryOperator::Less,
+                "<=" => BinaryOperator::LessOrEqual,
+                ">" => BinaryOperator::Greater,
+                ">=" => BinaryOperator::GreaterOrEqual,
+                "==" => BinaryOperator::Equal,
+                "!=" => BinaryOperator::NotEqual,
+                "|" => BinaryOperator::Or,
+                _ => return None,
+            },
+            // after an operand & is always an and, it only takes a label in front of a name where an operand starts,
+            // so `a &b` and `a & b` are both a and the constant b, and a label is anded with `a & &b`.
+            IDGrab(_) => BinaryOperator::And,
+            Number(text) if text.starts_with(['-', '+']) && operators.contains(&BinaryOperator::Add) => {
+                let operator = if text.starts_with('-') { BinaryOperator::Subtract } else { BinaryOperator::Add };
+                self.split = Some(Span { start: token.span.start + 1, ..token.span });
+                self.position += 1;
+                return Some((operator, Span { end: token.span.start + 1, ..token.span }));
+            },
+            _ => return None,
+        };
+        if !operators.contains(&operator) {
+            return None;
+        }
+        self.position += 1;
+        Some((operator, token.span))
+    }
+
+    fn unary(&mut self) -> Result<Expression, Diagnostic> {
+        use ArsenalToken::*;
+        if let Some(span) = self.split.take() {
+            let text = self.sources.text(span);
+            return text.parse().map(Expression::Number).map_err(|_| self.sources.error(span, format!("`{}` is too large", text)));
+        }
+        let Some(token) = self.peek() else {
+            return Err(self.unexpected("an expression"));
+        };
+        let span = token.span;
+        self.position += 1;
+        match &token.token {
+            Operator(text) if text == "-" => Ok(Expression::Negate(Box::new(self.unary()?), span)),
+            Operator(text) if text == "~" => Ok(Expression::Not(Box::new(self.unary()?))),
+            Operator(text) if text == "+" => self.unary(),
+            Number(text) => text.parse().map(Expression::Number).map_err(|_| self.sources.error(span, format!("`{}` is too large", text))),
+            Hex(text) => u128::from_str_radix(&text[2..], 16).ok().and_then(|value| i128::try_from(value).ok())
+                .map(Expression::Number).ok_or_else(|| self.sources.error(span, format!("`{}` is too large", text))),
+            IDGrab(_) => match label_reference(self.tokens, &mut self.position) {
+                Some((name, span)) => Ok(Expression::Label(name, span)),
+                None => Err(self.unexpected("a label name after &")),
+            },
+            SizeGrab(_) => Ok(Expression::Size(self.name("a size name after $")?, self.tokens[self.position - 1].span)),
+            Identifier(name) if register_index(name).is_some() => Err(self.sources.error(span, format!("{} is a register, it cannot be used in an expression", name))),
+            Identifier(name) => Ok(Expression::Constant(name.clone(), span)),
+            OpenParen(_) => {
+                let inner = self.binary(0)?;
+                match self.peek() {
+                    Some(Spanned { token: ClosedParen(_), .. }) => {
+                        self.position += 1;
+                        Ok(inner)
+                    },
+                    _ => Err(self.unexpected("`)` or an operator")),
+                }
+            },
+            _ => {
+                self.position -= 1;
+                Err(self.unexpected("a number, name or `(`"))
+            },
+        }
+    }
+
+    fn name(&mut self, expected: &str) -> Result<String, Diagnostic> {
+        match self.peek() {
+            Some(Spanned { token: ArsenalToken::Identifier(name), .. }) => {
+                self.position += 1;
+                Ok(name.clone())
+            },
+            _ => Err(self.unexpected(expected)),
+        }
+    }
+}
+
+impl Expression {
+    // gives every label the name it is stored under, see Parser::label_key.
+    pub fn rename_labels<E>(&mut self, rename: &mut impl FnMut(&str, Span) -> Result<String, E>) -> Result<(), E> {
+        match self {
+            Expression::Label(name, span) => *name = rename(name, *span)?,
+            Expression::Negate(inner, _) | Expression::Not(inner) => inner.rename_labels(rename)?,
+            Expression::Binary(_, left, right, _) => {
+                left.rename_labels(rename)?;
+                right.rename_labels(rename)?;
+            },
+            Expression::Number(_) | Expression::Size(..) | Expression::Constant(..) => {},
+        }
+        Ok(())
+    }
+
+    // labels, sizes and constants are looked up through resolve, everything else is checked for overflow here.
+    pub fn evaluate(&self, sources: &SourceMap, resolve: &mut dyn FnMut(&Expression) -> Result<i128, Diagnostic>) -> Result<i128, Diagnostic> {
+        use BinaryOperator::*;
+        let overflow = |span: Span| sources.error(span, "the expression overflows");
+        match self {
+            Expression::Number(value) => Ok(*value),
+            Expression::Label(..) | Expression::Size(..) | Expression::Constant(..) => resolve(self),
+            Expression::Negate(inner, span) => inner.evaluate(sources, resolve)?.checked_neg().ok_or_else(|| overflow(*span)),
+            Expression::Not(inner) => Ok(!inner.evaluate(sources, resolve)?),
+            Expression::Binary(operator, left, right, span) => {
+                let (left, right) = (left.evaluate(sources, resolve)?, right.evaluate(sources, resolve)?);
+                let shift = || u32::try_from(right).ok().filter(|shift| *shift < 128)
+                    .ok_or_else(|| sources.error(*span, format!("cannot shift by {}", right)));
+                match operator {
+                    Multiply => left.checked_mul(right).ok_or_else(|| overflow(*span)),
+                    Divide if right == 0 => Err(sources.error(*span, "division by zero")),
+                    Divide => left.checked_div(right).ok_or_else(|| overflow(*span)),
+                    Add => left.checked_add(right).ok_or_else(|| overflow(*span)),
+                    Subtract => left.checked_sub(right).ok_or_else(|| overflow(*span)),
+                    ShiftLeft => {
+                        let shift = shift()?;
+                        let shifted = left << shift;
+                        if shifted >> shift == left { Ok(shifted) } else { Err(overflow(*span)) }
+                    },
+                    ShiftRight => Ok(left >> shift()?),
+                    // comparisons give 1 or 0.
+                    Less => Ok((left < right) as i128),
+                    LessOrEqual => Ok((left <= right) as i128),
+                    Greater => Ok((left > right) as i128),
+                    GreaterOrEqual => Ok((left >= right) as i128),
+                    Equal => Ok((left == right) as i128),
+                    NotEqual => Ok((left != right) as i128),
+                    And => Ok(left & right),
+                    Or => Ok(left | right),
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::Source;
+    use crate::tokenizer::tokenize;
+
+    // parses the whole text as one expression, labels are worth 100 and constants a and b are 6 and 3.
+    fn evaluate(text: &str) -> Result<i128, String> {
+        let mut sources = SourceMap::default();
+        let id = sources.add(Source { name: "test.ars".to_string(), text: text.to_string(), included_from: None });
+        let (tokens, _) = tokenize::<ArsenalToken>(text, id);
+        let mut position = 0;
+        let (expression, _) = parse(&sources, &tokens, &mut position).map_err(|diagnostic| diagnostic.message)?;
+        if let Some(rest) = tokens.get(position) {
+            return Err(format!("stopped at `{}`", rest.token.text()));
+        }
+        expression.evaluate(&sources, &mut |reference| match reference {
+            Expression::Label(..) => Ok(100),
+            Expression::Constant(name, span) => match name.as_str() {
+                "a" => Ok(6),
+                "b" => Ok(3),
+                _ => Err(sources.error(*span, format!("unknown constant {}", name))),
+            },
+            _ => Ok(0),
+        }).map_err(|diagnostic| diagnostic.message)
+    }
+
+    const MAX: &str = "170141183460469231731687303715884105727";
+
+    #[test]
+    fn operators_bind_like_in_c() {
+        assert_eq!(evaluate("1 + 2 * 3"), Ok(7));
+        assert_eq!(evaluate("(1 + 2) * 3"), Ok(9));
+        assert_eq!(evaluate("1 << 2 + 1"), Ok(8));
+        assert_eq!(evaluate("12 - 4 - 2"), Ok(6));
+        assert_eq!(evaluate("16 / 4 / 2"), Ok(2));
+        assert_eq!(evaluate("1 | 6 & 3"), Ok(3));
+        assert_eq!(evaluate("6 & 3 == 3"), Ok(0));
+        assert_eq!(evaluate("2 < 3 == 1"), Ok(1));
+        assert_eq!(evaluate("~1 & 7"), Ok(6));
+        assert_eq!(evaluate("-a * -b"), Ok(18));
+    }
+
+    #[test]
+    fn overflow_is_an_error() {
+        assert_eq!(evaluate(&format!("{MAX} + 1")), Err("the expression overflows".to_string()));
+        assert_eq!(evaluate(&format!("-{MAX} - 2")), Err("the expression overflows".to_string()));
+        assert_eq!(evaluate(&format!("{MAX} * 2")), Err("the expression overflows".to_string()));
+        assert_eq!(evaluate("1 << 127"), Err("the expression overflows".to_string()));
+        assert_eq!(evaluate("1 << 128"), Err("cannot shift by 128".to_string()));
+        assert_eq!(evaluate("a / (b - 3)"), Err("division by zero".to_string()));
+        assert_eq!(evaluate(&format!("-{MAX} - 1")), Ok(i128::MIN));
+        assert_eq!(evaluate("0x80000000000000000000000000000000"), Err("`0x80000000000000000000000000000000` is too large".to_string()));
+    }
+
+    #[test]
+    fn spacing_does_not_change_the_meaning() {
+        for (spaced, tight) in [("a - 1", "a -1"), ("a - b", "a -b"), ("a + 1", "a +1"), ("a & b", "a &b"), ("a & -1", "a &-1"), ("a - -1", "a--1")] {
+            assert_eq!(evaluate(spaced), evaluate(tight), "{} and {}", spaced, tight);
+        }
+        assert_eq!(evaluate("6-1"), Ok(5));
+        assert_eq!(evaluate(&format!("a - {MAX}0")), Err(format!("`{MAX}0` is too large")));
+        assert_eq!(evaluate(&format!("a -{MAX}0")), Err(format!("`{MAX}0` is too large")));
+    }
+
+    #[test]
+    fn ampersand_takes_a_label_only_where_an_operand_starts() {
+        assert_eq!(evaluate("&x"), Ok(100));
+        assert_eq!(evaluate("&x & 0xf"), Ok(4));
+        assert_eq!(evaluate("a & &x"), Ok(4));
+        assert_eq!(evaluate("(&x) - 1"), Ok(99));
+        assert_eq!(evaluate("a &x"), Err("unknown constant x".to_string()));
+    }
+}
@@ -2,219 +2,818 @@
 extern crate arsenal_globals;
 
 pub mod tokenizer;
+pub mod diagnostic;
+pub mod mnemonics;
+pub mod preprocessor;
+pub mod expression;
 
-use core::panic;
-use std::{collections::HashMap, str::FromStr, io::Read};
+use std::{collections::HashMap, str::FromStr};
 
-use arsenal_globals::{SysCalls, ArsenalObject};
+use arsenal_globals::{SysCalls, ArsenalObject, Operand, register_index};
 use strum::VariantNames;
 
+use tokenizer::{ArsenalToken, Span, Spanned};
+use diagnostic::{Diagnostic, SourceMap, did_you_mean};
+use mnemonics::{MNEMONICS, Shape, Slot};
+use preprocessor::Preprocessor;
+use expression::Expression;
+
 enum DataObject {
     Byte(u8),
-    LabelRequest(String, u32, u32, u64),
-    SizeRequest(String, u32, u32),
+    LabelRequest(String, u32, u32, u64, Span),
+    SizeRequest(String, u32, u32, Span),
+    ExpressionRequest(Expression, u32, u32, Span),
+}
+
+#[derive(Default)]
+struct Section {
+    data: Vec<DataObject>,
+    bytes_count: usize,
+}
+
+impl Section {
+    fn push_byte(&mut self, byte: u8) {
+        self.data.push(DataObject::Byte(byte));
+        self.bytes_count += 1;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ArgumentKind {
+    Register,
+    RegisterPair,
+    Bytes,
+}
+
+// one argument of a statement, how many bytes it became and its value when it was a plain number.
+struct Argument {
+    span: Span,
+    size: usize,
+    value: Option<u64>,
+    kind: ArgumentKind,
 }
 
-pub fn new_parse(data: Vec<u8>) -> Option<ArsenalObject> {
-    let as_str = match String::from_utf8(data) {
-        Ok(str) => str,
-        Err(_) => return None,
+// an operand of a short form like `mov.q r1, [r2]`, register is only meaningful when there is no value.
+struct ShortOperand {
+    shape: Shape,
+    register: u8,
+    value: Option<(Expression, Span)>,
+}
+
+type ParseResult<T> = Result<T, Diagnostic>;
+
+struct Parser<'a> {
+    sources: &'a SourceMap,
+    tokens: &'a [Spanned<ArsenalToken>],
+    position: usize,
+    labels: HashMap<String, u64>,
+    // where each label was defined, to point at the first one when a name is used twice.
+    label_spans: HashMap<String, Span>,
+    // the global label local ones like .loop belong to, and how many `label 1:` of each number were seen.
+    scope: Option<String>,
+    anonymous: HashMap<String, usize>,
+    sizes: HashMap<String, u64>,
+    constants: HashMap<String, (Expression, Span)>,
+    text: Section,
+    tls: Section,
+    in_tls: bool,
+    host_syscalls: Vec<(String, u8)>,
+    macros: Vec<String>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+pub fn new_parse(file: &str, data: Vec<u8>) -> Result<ArsenalObject, Vec<Diagnostic>> {
+    parse_files(vec![(file.to_string(), data)], &[])
+}
+
+// the files make up one program, read in order as if each one included the next.
+// defines are constants given from outside, as if they were declared with .equ before the first file.
+// every problem in them is collected, a statement that fails to parse is skipped up to its ;.
+pub fn parse_files(files: Vec<(String, Vec<u8>)>, defines: &[(String, i128)]) -> Result<ArsenalObject, Vec<Diagnostic>> {
+    let mut sources = SourceMap::default();
+    let mut diagnostics = vec![];
+    let mut preprocessor = Preprocessor::new(&mut sources, &mut diagnostics);
+    for (name, value) in defines {
+        preprocessor.define_constant(name, *value);
+    }
+    for (name, data) in files {
+        preprocessor.file(&name, data);
+    }
+    let (tokens, macros) = preprocessor.finish();
+
+    let mut parser = Parser {
+        sources: &sources,
+        tokens: &tokens,
+        position: 0,
+        labels: HashMap::new(),
+        label_spans: HashMap::new(),
+        scope: None,
+        anonymous: HashMap::new(),
+        sizes: HashMap::new(),
+        constants: defines.iter().map(|(name, value)| (name.clone(), (Expression::Number(*value), Span::default()))).collect(),
+        text: Section::default(),
+        tls: Section::default(),
+        in_tls: false,
+        host_syscalls: vec![],
+        macros,
+        diagnostics,
     };
-    let mut tokens = tokenizer::tokenize::<tokenizer::ArsenalToken>(&as_str);
-    let mut tokens: std::iter::Peekable<std::slice::Iter<'_, tokenizer::ArsenalToken>> = tokens.iter().peekable();
-
-    let mut labels = HashMap::<String, u64>::new();
-    let mut sizes = HashMap::<String, u64>::new();
-    let mut data: Vec<DataObject> = vec![];
-    let mut bytes_count: usize = 0;
-
-    while let Some(token) = &tokens.next() {
-        use tokenizer::ArsenalToken::*;
-        match *token {
-            LineEnd(_) => continue,
+
+    while parser.peek().is_some() {
+        if let Err(diagnostic) = parser.statement() {
+            parser.diagnostics.push(diagnostic);
+            parser.recover();
+        }
+    }
+
+    let (text, tls) = (std::mem::take(&mut parser.text), std::mem::take(&mut parser.tls));
+    let data = parser.resolve_section(text);
+    let tls = parser.resolve_section(tls);
+    parser.check_constants();
+
+    if !parser.diagnostics.is_empty() {
+        let mut diagnostics = parser.diagnostics;
+        diagnostics.sort_by_key(|diagnostic| (sources.position(&diagnostic.file), diagnostic.line, diagnostic.column));
+        // a broken constant is reported once however often it is used.
+        diagnostics.dedup();
+        return Err(diagnostics);
+    }
+
+    Ok(ArsenalObject::ArsenalCompiledObject { data, tls, host_syscalls: parser.host_syscalls })
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Spanned<ArsenalToken>> {
+        self.tokens.get(self.position)
+    }
+
+    fn advance(&mut self) -> Option<&'a Spanned<ArsenalToken>> {
+        let token = self.peek();
+        self.position += token.is_some() as usize;
+        token
+    }
+
+    fn error(&self, span: Span, message: impl Into<String>) -> Diagnostic {
+        self.sources.error(span, message)
+    }
+
+    // complains about the next token without taking it, so recovery can still see a ; or label there.
+    fn unexpected(&self, expected: &str) -> Diagnostic {
+        match self.peek() {
+            Some(found) => self.error(found.span, format!("expected {}, found `{}`", expected, found.token.text())),
+            None => {
+                let end = self.tokens.last().map_or(Span::default(), |last| Span { start: last.span.end, ..last.span });
+                self.error(end, format!("expected {}, found the end of the file", expected))
+            },
+        }
+    }
+
+    fn expect(&mut self, matches: fn(&ArsenalToken) -> bool, expected: &str) -> ParseResult<&'a Spanned<ArsenalToken>> {
+        match self.peek() {
+            Some(token) if matches(&token.token) => Ok(self.advance().unwrap()),
+            _ => Err(self.unexpected(expected)),
+        }
+    }
+
+    fn expect_identifier(&mut self, expected: &str) -> ParseResult<(&'a String, Span)> {
+        match self.expect(|token| matches!(token, ArsenalToken::Identifier(_)), expected)? {
+            Spanned { token: ArsenalToken::Identifier(name), span } => Ok((name, *span)),
+            _ => unreachable!(),
+        }
+    }
+
+    fn expect_number<T: FromStr>(&mut self, expected: &str, range: &str) -> ParseResult<T> {
+        let token = self.expect(|token| matches!(token, ArsenalToken::Number(_)), expected)?;
+        token.token.text().parse().map_err(|_| self.error(token.span, format!("`{}` does not fit in {}", token.token.text(), range)))
+    }
+
+    fn expect_line_end(&mut self, after: &str) -> ParseResult<()> {
+        self.expect(|token| matches!(token, ArsenalToken::LineEnd(_)), &format!("`;` after {}", after)).map(|_| ())
+    }
+
+    // skips the rest of a broken statement, a label always starts a new one so it is left alone.
+    // errors found once the ; was read, like a bad operand list, have nothing left to skip.
+    fn recover(&mut self) {
+        if let Some(Spanned { token: ArsenalToken::LineEnd(_), .. }) = self.position.checked_sub(1).map(|last| &self.tokens[last]) {
+            return;
+        }
+        while let Some(token) = self.peek() {
+            match token.token {
+                ArsenalToken::Label(_) => return,
+                ArsenalToken::LineEnd(_) => {
+                    self.advance();
+                    return;
+                },
+                _ => { self.advance(); },
+            }
+        }
+    }
+
+    fn section(&mut self) -> &mut Section {
+        if self.in_tls { &mut self.tls } else { &mut self.text }
+    }
+
+    fn statement(&mut self) -> ParseResult<()> {
+        use ArsenalToken::*;
+        let Some(token) = self.advance() else { return Ok(()) };
+        match &token.token {
+            LineEnd(_) => Ok(()),
             Label(_) => {
-                if let Some(Identifier(name)) = tokens.next() {
-                    labels.insert(name.clone(), bytes_count as u64);
-                    if let Some(Selection(_)) = tokens.next() {} else {
-                        panic!("expected line ending after identifier");
+                let name = self.expect(|token| matches!(token, Identifier(_) | SpecialIdentifier(_) | Number(_)), "a label name after `label`")?;
+                let key = match &name.token {
+                    Number(number) if number.starts_with(['-', '+']) => {
+                        return Err(self.error(name.span, "anonymous labels are numbered without a sign"));
+                    },
+                    Number(number) => {
+                        let count = self.anonymous.entry(number.clone()).or_insert(0);
+                        *count += 1;
+                        format!("{}@{}", number, count)
+                    },
+                    _ => self.label_key(name.token.text(), name.span)?,
+                };
+                // generated names like the labels inside macros do not start a new scope.
+                if let Identifier(global) = &name.token {
+                    if !global.starts_with("__") {
+                        self.scope = Some(global.clone());
                     }
-                } else {
-                    panic!("expected an identifier after label");
                 }
+                let offset = self.section().bytes_count as u64;
+                self.define_label(key, name.span, offset)?;
+                self.expect(|token| matches!(token, Selection(_)), "`:` after the label name")?;
+                Ok(())
             },
             Identifier(name) => {
-                if let Ok(instruction) = arsenal_globals::Instructions::from_str(name) {
-                    data.push(DataObject::Byte(instruction as u8));
-                    data.push(DataObject::Byte((instruction as u16).wrapping_shr(8) as u8));
-                    bytes_count += 2;
+                let Ok(instruction) = arsenal_globals::Instructions::from_str(name) else {
+                    if MNEMONICS.contains(&name.as_str()) {
+                        return self.short_instruction(name, token.span);
+                    }
+                    let instructions = arsenal_globals::Instructions::VARIANTS.iter().chain(MNEMONICS).copied();
+                    let suggestion = did_you_mean(name, instructions.chain(self.macros.iter().map(String::as_str)));
+                    return Err(self.error(token.span, format!("unknown instruction `{}`{}", name, suggestion)));
+                };
+                self.section().push_byte(instruction as u8);
+                self.section().push_byte((instruction as u16).wrapping_shr(8) as u8);
+                let arguments = self.parse_arg_sequence()?;
+                self.check_operands(name, instruction, &arguments)
+            },
+            OpenParen(_) => self.data_block(),
+            SpecialIdentifier(directive) => self.directive(directive, token.span),
+            unexpected => Err(self.error(token.span, format!("expected an instruction, label or data block, found `{}`", unexpected.text()))),
+        }
+    }
 
-                    parse_arg_sequence(&mut tokens, &mut data, &mut bytes_count);
+    fn short_instruction(&mut self, mnemonic: &str, span: Span) -> ParseResult<()> {
+        use ArsenalToken::*;
+        let suffix = match self.peek() {
+            Some(Spanned { token: SpecialIdentifier(suffix), span: suffix_span }) if suffix_span.start == span.end => {
+                self.advance();
+                if !mnemonics::is_width_suffix(&suffix[1..]) {
+                    return Err(self.error(*suffix_span, format!("unknown width suffix {}, use .b .w .d or .q", suffix)));
                 }
+                Some(&suffix[1..])
             },
-            OpenParen(_) => {
-                match tokens.next() {
-                    Some(ClosedParen(_)) => {
-                        let Identifier(name) = tokens.next().expect(&format!("expected identifier after (), got nothing")) else { panic!("expected identifier after ()"); };
-                        assert!(matches!(tokens.next().expect("() name expression require setter =, size cannot be inferred."), VarAssignment(_)));
-                        labels.insert(name.clone(), bytes_count as u64);
+            _ => None,
+        };
 
-                        parse_arg_sequence(&mut tokens, &mut data, &mut bytes_count);
-                    },
-                    Some(Identifier(size)) => {
-                        assert!(matches!(tokens.next().expect("expected ) after (capture"), ClosedParen(_)));
-                        let Identifier(name) = tokens.next().expect(&format!("expected identifier after (capture), got nothing")) else { panic!("expected identifier after (capture)"); };
-
-                        assert!(matches!(tokens.next().expect("(capture) name expression require setter =, size cannot be inferred."), VarAssignment(_)));
-                        labels.insert(name.clone(), bytes_count as u64);
-                        let current_count = bytes_count;
-                        parse_arg_sequence(&mut tokens, &mut data, &mut bytes_count);
-                        sizes.insert(size.clone(), (bytes_count - current_count) as u64);
-                    },
-                    Some(Number(num)) => {},
-                    Some(Hex(num)) => {},
+        let mut operands = vec![];
+        if !matches!(self.peek(), Some(Spanned { token: LineEnd(_), .. })) {
+            operands.push(self.short_operand()?);
+            while let Some(Spanned { token: Separator(_), .. }) = self.peek() {
+                self.advance();
+                operands.push(self.short_operand()?);
+            }
+        }
+        self.expect_line_end("the operands")?;
 
-                    Some(unexpected) => {panic!("expected number, identifier, or ) after (, not {:?}", unexpected)}
-                    None => panic!("Expected token after ("),
-                }
+        let statement = span.to(self.tokens[self.position - 1].span);
+        let shapes: Vec<Shape> = operands.iter().map(|operand| operand.shape).collect();
+        let (instruction, slots) = mnemonics::select(mnemonic, suffix, &shapes).map_err(|message| self.error(statement, message))?;
+
+        self.section().push_byte(instruction as u8);
+        self.section().push_byte((instruction as u16).wrapping_shr(8) as u8);
+        for (operand, slot) in instruction.operands().iter().zip(slots) {
+            match slot {
+                Slot::Pair(high, low) => {
+                    let pair = operands[high].register << 4 | operands[low].register;
+                    self.section().push_byte(pair);
+                },
+                Slot::Operand(index) => match &operands[index].value {
+                    Some(value) => self.push_value(value, operand.size())?,
+                    None => {
+                        let register = operands[index].register;
+                        self.section().push_byte(register);
+                    },
+                },
             }
+        }
+        Ok(())
+    }
 
-            SpecialIdentifier(_) => todo!(),
+    fn short_operand(&mut self) -> ParseResult<ShortOperand> {
+        use ArsenalToken::*;
+        let Some(token) = self.peek() else {
+            return Err(self.unexpected("an operand"));
+        };
+        let value = match &token.token {
+            OpenBracket(_) => {
+                self.advance();
+                let inner = self.short_operand()?;
+                self.expect(|token| matches!(token, ClosedBracket(_)), "`]` to close the address")?;
+                let shape = match inner.shape {
+                    Shape::Register => Shape::AddressedRegister,
+                    Shape::Immediate => Shape::Memory,
+                    _ => return Err(self.error(token.span, "addresses cannot be nested")),
+                };
+                return Ok(ShortOperand { shape, ..inner });
+            },
+            Identifier(name) if register_index(name).is_some() => {
+                self.advance();
+                return Ok(ShortOperand { shape: Shape::Register, register: register_index(name).unwrap(), value: None });
+            },
+            NumericSlice(_) => {
+                self.advance();
+                self.expression()?
+            },
+            _ => self.expression()?,
+        };
+        Ok(ShortOperand { shape: Shape::Immediate, register: 0, value: Some(value) })
+    }
 
-            Whitespace(_) => unreachable!(),
+    fn expression(&mut self) -> ParseResult<(Expression, Span)> {
+        let (mut expression, span) = expression::parse(self.sources, self.tokens, &mut self.position)?;
+        expression.rename_labels(&mut |name, span| self.label_key(name, span))?;
+        Ok((expression, span))
+    }
 
-            unexpected => panic!("unexpected token {:?}", unexpected),
+    // the name a label is stored under, .loop belongs to the global label above it as name.loop,
+    // 1b is the latest `label 1:` so far and 1f the next one, stored as 1@n for the n-th of them.
+    fn label_key(&self, name: &str, span: Span) -> ParseResult<String> {
+        if name.starts_with('.') {
+            return match &self.scope {
+                Some(scope) => Ok(format!("{}{}", scope, name)),
+                None => Err(self.error(span, format!("local label {} needs a global label before it", name))),
+            };
         }
+        let (number, direction) = name.split_at(name.len() - 1);
+        if !number.is_empty() && number.bytes().all(|byte| byte.is_ascii_digit()) {
+            let count = self.anonymous.get(number).copied().unwrap_or(0);
+            return match direction {
+                "b" if count == 0 => Err(self.error(span, format!("there is no `label {}:` before {}", number, name))),
+                "b" => Ok(format!("{}@{}", number, count)),
+                _ => Ok(format!("{}@{}", number, count + 1)),
+            };
+        }
+        Ok(name.to_string())
     }
 
-    let mut return_data: Vec<u8> = vec![];
+    fn define_label(&mut self, key: String, span: Span, offset: u64) -> ParseResult<()> {
+        if let Some(first) = self.label_spans.get(&key) {
+            let first = self.error(*first, "");
+            let name = self.sources.text(span);
+            return Err(self.error(span, format!("label {} is already defined at {}:{}", name, first.file, first.line)));
+        }
+        self.label_spans.insert(key.clone(), span);
+        self.labels.insert(key, offset);
+        Ok(())
+    }
 
-    for obj in data {
-        match obj {
-            DataObject::Byte(x) => return_data.push(x),
-            DataObject::LabelRequest(name, start, stop, inc) => {
-                let location = *labels.get(&name).expect(&format!("unknown label {}", name)) + inc;
-                for offset in (start as u32)..=(stop as u32) {
-                    unsafe {
-                        return_data.push(*(((&location as *const u64 as u64) + (offset) as u64) as *const u64 as *const u8));
-                    }
+    fn unknown_label(&self, key: &str, span: Span) -> Diagnostic {
+        match key.split_once('@') {
+            Some((number, _)) => self.error(span, format!("there is no `label {}:` after {}", number, self.sources.text(span))),
+            None => self.error(span, format!("unknown label {}", self.sources.text(span))),
+        }
+    }
+
+    // numbers may be written signed or unsigned as long as they fit the operand, plain labels and sizes are cut to it.
+    // anything computed is checked once its value is known.
+    fn push_value(&mut self, (value, span): &(Expression, Span), size: usize) -> ParseResult<()> {
+        let last = size as u32 - 1;
+        match value {
+            Expression::Number(number) => {
+                let bits = size as u32 * 8;
+                if *number < -(1i128 << (bits - 1)) || *number >= 1i128 << bits {
+                    return Err(self.error(*span, format!("`{}` does not fit in {} bits", number, bits)));
                 }
-            },
-            DataObject::SizeRequest(name, start, stop) => {
-                let location = *sizes.get(&name).expect(&format!("unknown size id {}", name));
-                for offset in (start as u32)..=(stop as u32) {
-                    unsafe {
-                        return_data.push(*(((&location as *const u64 as u64) + (offset) as u64) as *const u64 as *const u8));
-                    }
+                for byte in &(*number as u64).to_ne_bytes()[..size] {
+                    self.section().push_byte(*byte);
                 }
+                return Ok(());
+            },
+            Expression::Label(name, span) => {
+                self.section().data.push(DataObject::LabelRequest(name.clone(), 0, last, 0, *span));
+            },
+            Expression::Size(name, span) => {
+                self.section().data.push(DataObject::SizeRequest(name.clone(), 0, last, *span));
+            },
+            _ => {
+                self.section().data.push(DataObject::ExpressionRequest(value.clone(), 0, last, *span));
             },
         }
+        self.section().bytes_count += size;
+        Ok(())
     }
 
-    assert!(return_data.len() == bytes_count);
+    fn data_block(&mut self) -> ParseResult<()> {
+        use ArsenalToken::*;
+        let size = match self.peek() {
+            Some(Spanned { token: ClosedParen(_), .. }) => {
+                self.advance();
+                None
+            },
+            Some(Spanned { token: Identifier(_), .. }) => {
+                let (size, _) = self.expect_identifier("a size name")?;
+                self.expect(|token| matches!(token, ClosedParen(_)), "`)` after the size name")?;
+                Some(size)
+            },
+            Some(Spanned { token: Number(_) | Hex(_), span }) => return Err(self.error(*span, "data blocks with a fixed size are not supported")),
+            _ => return Err(self.unexpected("`)` or a size name after `(`")),
+        };
+        let (name, name_span) = self.expect_identifier("a name for the data block")?;
+        self.expect(|token| matches!(token, VarAssignment(_)), "`=` after the data block name, its size cannot be inferred")?;
 
-    Some(ArsenalObject::ArsenalCompiledObject { data: return_data })
-}
+        let start = self.section().bytes_count;
+        self.define_label(name.clone(), name_span, start as u64)?;
+        let result = self.parse_arg_sequence().map(|_| ());
+        // the size is recorded even when the block is broken so uses of it don't pile on more errors.
+        if let Some(size) = size {
+            let length = (self.section().bytes_count - start) as u64;
+            self.sizes.insert(size.clone(), length);
+        }
+        result
+    }
 
-fn parse_arg_sequence(tokens: &mut std::iter::Peekable<std::slice::Iter<'_, tokenizer::ArsenalToken>>, data: &mut Vec<DataObject>, bytes_count: &mut usize) {
-    'main: while let Some(arg) = tokens.next() {
-        use tokenizer::ArsenalToken::*;
-        match arg {
-            LineEnd(_) => break 'main,
-            Separator(_) => todo!(),
-            Identifier(syscall) => {
-                if let Ok(id) = SysCalls::from_str(&syscall) {
-                    data.push(DataObject::Byte(id as u8));
-                    *bytes_count += 1;
-                } else {
-                    panic!("unknown syscall {}", syscall);
+    fn directive(&mut self, directive: &str, span: Span) -> ParseResult<()> {
+        match directive {
+            ".tls" => self.in_tls = true,
+            ".text" => self.in_tls = false,
+            ".syscall" => {
+                let (name, name_span) = self.expect_identifier("a syscall name after .syscall")?;
+                if SysCalls::from_str(name).is_ok() {
+                    return Err(self.error(name_span, format!("{} is already a builtin syscall", name)));
                 }
-            },
-            Hex(num) => {
-                let num = u8::from_str_radix(&num[2..], 16).unwrap();
-                data.push(DataObject::Byte(num));
-                *bytes_count += 1;
-            },
-            Number(num) => {
-                let num = num.parse().unwrap();
-                data.push(DataObject::Byte(num));
-                *bytes_count += 1;
-            },
-            StringLiteral(lit) => {
-                let to_put = lit[1..lit.len()-1].to_string();
-                for byte in to_put.as_bytes() {
-                    data.push(DataObject::Byte(*byte));
-                    *bytes_count += 1;
+                if register_index(name).is_some() {
+                    return Err(self.error(name_span, format!("{} is a register name", name)));
                 }
-            },
-            IDGrab(_) => {
-                let Identifier(name) = tokens.next().expect(&format!("expected identifier after &, got nothing")) else { panic!("expected identifier after &"); };
-                let (mut start, mut stop, mut extent) = (0, 7, 0);
-
-                if let Some(Selection(_)) = tokens.peek() {
-                    tokens.next();
-                    let Number(start_str) = tokens.next().expect("expected a number after (&name:)") else { panic!("expected a number after (&name:)"); };
-                    start = start_str.parse().expect("(&name:) must be followed by a number.");
-                    if let Some(Range(_)) = tokens.peek() {
-                        tokens.next();
-                        let Number(stop_str) = tokens.next().expect("expected a number after (&name:num->)") else { panic!("expected a number after (&name:num->)"); };
-                        stop = stop_str.parse().expect("(&name:num->) must be followed by a number.");
-                        if let Some(Shift(_)) = tokens.peek() {
-                            tokens.next();
-                            let Number(extent_str) = tokens.next().expect("expected a number after (&name:num->num=>)") else { panic!("expected a number after (&name:num->num=>)"); };
-                            extent = extent_str.parse().expect("(&name:num->num=>) must be followed by a number.");
-                        }
+                if !self.host_syscalls.iter().any(|(declared, _)| declared == name) {
+                    // host ids count down from the top so they never meet the builtin ones.
+                    let id = u8::MAX as usize - self.host_syscalls.len();
+                    if id < SysCalls::__END__ as usize {
+                        return Err(self.error(name_span, "too many host syscalls"));
                     }
+                    self.host_syscalls.push((name.clone(), id as u8));
                 }
-
-                *bytes_count += ((stop - start) + 1);
-                data.push(DataObject::LabelRequest(name.clone(), start as u32, stop as u32, extent as u64));
             },
+            ".equ" | ".const" => {
+                let (name, name_span) = self.expect_identifier(&format!("a constant name after {}", directive))?;
+                if register_index(name).is_some() {
+                    return Err(self.error(name_span, format!("{} is a register name", name)));
+                }
+                if self.constants.contains_key(name) {
+                    return Err(self.error(name_span, format!("constant {} is already defined", name)));
+                }
+                self.expect(|token| matches!(token, ArsenalToken::VarAssignment(_)), "`=` after the constant name")?;
+                let value = self.expression()?;
+                self.constants.insert(name.clone(), value);
+            },
+            unknown => return Err(self.error(span, format!("unknown directive {}", unknown))),
+        }
+        self.expect_line_end(directive)
+    }
 
-            SizeGrab(_) => {
-                let Identifier(name) = tokens.next().expect(&format!("expected identifier after $, got nothing")) else { panic!("expected identifier after $"); };
-                let (mut start, mut stop, mut extent) = (0, 7, 0);
+    // the :start->stop=>offset selection after a label, size or number, stop defaults to 7 and offset to 0.
+    fn byte_selection(&mut self, kind: &str, allow_offset: bool) -> ParseResult<(u32, u32, u64)> {
+        use ArsenalToken::*;
+        let (mut start, mut stop, mut offset) = (0, 7, 0);
+        let Some(Spanned { token: Selection(_), span: selection_span }) = self.peek() else {
+            return Ok((start, stop, offset));
+        };
+        self.advance();
+        start = self.expect_number(&format!("a number after {}:", kind), "a byte index")?;
+        if let Some(Spanned { token: Range(_), .. }) = self.peek() {
+            self.advance();
+            stop = self.expect_number(&format!("a number after {}:num->", kind), "a byte index")?;
+            if let (true, Some(Spanned { token: Shift(_), .. })) = (allow_offset, self.peek()) {
+                self.advance();
+                offset = self.expect_number(&format!("a number after {}:num->num=>", kind), "64 bits")?;
+            }
+        }
+        if start > stop || stop > 7 {
+            return Err(self.error(selection_span.to(self.tokens[self.position - 1].span), format!("byte selection {}->{} must count up within 0->7", start, stop)));
+        }
+        Ok((start, stop, offset))
+    }
 
-                if let Some(Selection(_)) = tokens.peek() {
-                    tokens.next();
-                    let Number(start_str) = tokens.next().expect("expected a number after ($name:)") else { panic!("expected a number after $name:)"); };
-                    start = start_str.parse().expect("($name:) must be followed by a number.");
-                    if let Some(Range(_)) = tokens.peek() {
-                        tokens.next();
-                        let Number(stop_str) = tokens.next().expect("expected a number after ($name:num->)") else { panic!("expected a number after ($name:num->)"); };
-                        stop = stop_str.parse().expect("($name:num->) must be followed by a number.");
+    fn parse_arg_sequence(&mut self) -> ParseResult<Vec<Argument>> {
+        use ArsenalToken::*;
+        let mut arguments = vec![];
+        loop {
+            let Some(arg) = self.advance() else {
+                return Err(self.unexpected("`;` at the end of the statement"));
+            };
+            let bytes_before = self.section().bytes_count;
+            let mut value = None;
+            let mut kind = ArgumentKind::Bytes;
+            match &arg.token {
+                LineEnd(_) => return Ok(arguments),
+                Identifier(name) if register_index(name).is_some() => {
+                    let first = register_index(name).unwrap();
+                    kind = ArgumentKind::Register;
+                    value = Some(first as u64);
+                    // `r1, r5` packs into one byte, the first register in the high nibble.
+                    if let Some(Spanned { token: Separator(_), .. }) = self.peek() {
+                        self.advance();
+                        let (second, span) = self.expect_identifier("a second register after `,`")?;
+                        let second = register_index(second).ok_or_else(|| self.error(span, format!("`{}` is not a register", second)))?;
+                        kind = ArgumentKind::RegisterPair;
+                        value = Some((first << 4 | second) as u64);
                     }
-                }
-
-                *bytes_count += ((stop - start) + 1);
-                data.push(DataObject::SizeRequest(name.clone(), start as u32, stop as u32));
-            },
+                    self.section().push_byte(value.unwrap() as u8);
+                },
+                Separator(_) => return Err(self.error(arg.span, "`,` can only join the two registers of a pair like `r1, r5`")),
+                Identifier(syscall) => {
+                    let id = match SysCalls::from_str(syscall) {
+                        Ok(id) => id as u8,
+                        Err(_) => match self.host_syscalls.iter().find(|(name, _)| name == syscall) {
+                            Some((_, id)) => *id,
+                            None if syscall.strip_prefix('r').is_some_and(|number| number.parse::<u64>().is_ok()) => {
+                                return Err(self.error(arg.span, format!("there is no register {}, registers go from r0 to r15", syscall)));
+                            },
+                            None => {
+                                let candidates = SysCalls::VARIANTS.iter().copied().chain(self.host_syscalls.iter().map(|(name, _)| name.as_str()));
+                                return Err(self.error(arg.span, format!("unknown syscall `{}`{}", syscall, did_you_mean(syscall, candidates))));
+                            },
+                        },
+                    };
+                    self.section().push_byte(id);
+                    value = Some(id as u64);
+                },
+                Hex(num) => {
+                    let num = u8::from_str_radix(&num[2..], 16).map_err(|_| self.error(arg.span, format!("`{}` does not fit in a byte", num)))?;
+                    self.section().push_byte(num);
+                    value = Some(num as u64);
+                },
+                Number(num) => {
+                    let num: u8 = num.parse().map_err(|_| self.error(arg.span, format!("`{}` does not fit in a byte", num)))?;
+                    self.section().push_byte(num);
+                    value = Some(num as u64);
+                },
+                StringLiteral(lit) => {
+                    for byte in &lit.as_bytes()[1..lit.len()-1] {
+                        self.section().push_byte(*byte);
+                    }
+                },
+                IDGrab(_) => {
+                    let Some((name, span)) = expression::label_reference(self.tokens, &mut self.position) else {
+                        return Err(self.unexpected("a label name after &"));
+                    };
+                    let name = self.label_key(&name, span)?;
+                    let (start, stop, extent) = self.byte_selection("&name", true)?;
+                    let section = self.section();
+                    section.bytes_count += ((stop - start) + 1) as usize;
+                    section.data.push(DataObject::LabelRequest(name.clone(), start, stop, extent, span));
+                },
+                SizeGrab(_) => {
+                    let (name, span) = self.expect_identifier("a size name after $")?;
+                    let (start, stop, _) = self.byte_selection("$name", false)?;
+                    let section = self.section();
+                    section.bytes_count += ((stop - start) + 1) as usize;
+                    section.data.push(DataObject::SizeRequest(name.clone(), start, stop, span));
+                },
+                // #NAME and #(expression) are worked out once every label is known.
+                NumericSlice(_) if matches!(self.peek(), Some(Spanned { token: Identifier(_) | OpenParen(_), .. })) => {
+                    let (mut expression, span) = expression::parse_operand(self.sources, self.tokens, &mut self.position)?;
+                    expression.rename_labels(&mut |name, span| self.label_key(name, span))?;
+                    let (start, stop, _) = self.byte_selection("#expression", false)?;
+                    let section = self.section();
+                    section.bytes_count += ((stop - start) + 1) as usize;
+                    section.data.push(DataObject::ExpressionRequest(expression, start, stop, span));
+                },
+                NumericSlice(_) => {
+                    let num: i64 = self.expect_number("a number, name or `(` after #", "64 bits")?;
+                    let (start, stop, _) = self.byte_selection("#num", false)?;
+                    for offset in start..=stop {
+                        self.section().push_byte(num.to_ne_bytes()[offset as usize]);
+                    }
+                },
+                unexpected => return Err(self.error(arg.span, format!("unexpected `{}` in an argument list", unexpected.text()))),
+            }
+            let span = arg.span.to(self.tokens[self.position - 1].span);
+            arguments.push(Argument { span, size: self.section().bytes_count - bytes_before, value, kind });
+        }
+    }
 
-            NumericSlice(_) => {
-                let Number(num) = tokens.next().expect(&format!("expected number after #, got nothing")) else { panic!("expected number after #"); };
-                let num: i64 = num.parse().unwrap();
-                let (mut start, mut stop) = (0, 7);
-
-                if let Some(Selection(_)) = tokens.peek() {
-                    tokens.next();
-                    let Number(start_str) = tokens.next().expect("expected a number after (#num:)") else { panic!("expected a number after #num:)"); };
-                    start = start_str.parse().expect("(#num:) must be followed by a number.");
-                    if let Some(Range(_)) = tokens.peek() {
-                        tokens.next();
-                        let Number(stop_str) = tokens.next().expect("expected a number after ($name:num->)") else { panic!("expected a number after ($name:num->)"); };
-                        stop = stop_str.parse().expect("($name:num->) must be followed by a number.");
+    // arguments are only bytes, so each operand is filled by as many arguments as it takes to reach its size.
+    fn check_operands(&self, name: &str, instruction: arsenal_globals::Instructions, arguments: &[Argument]) -> ParseResult<()> {
+        let operands = instruction.operands();
+        let names: Vec<String> = operands.iter().map(|operand| format!("a {}", operand.name())).collect();
+        let expected = match names.as_slice() {
+            [] => format!("`{}` takes no operands", name),
+            [only] => format!("`{}` takes {}", name, only),
+            [rest @ .., last] => format!("`{}` takes {} and {}", name, rest.join(", "), last),
+        };
+        let mut arguments = arguments.iter().peekable();
+        for operand in operands {
+            let mut filled = 0;
+            while filled < operand.size() {
+                let Some(argument) = arguments.next() else {
+                    let line_end = self.tokens[self.position - 1].span;
+                    return Err(self.error(line_end, format!("missing {} operand, {}", operand.name(), expected)));
+                };
+                filled += argument.size;
+                if filled > operand.size() {
+                    return Err(self.error(argument.span, format!(
+                        "{} byte argument does not fit the {} byte {} operand, {}", argument.size, operand.size(), operand.name(), expected)));
+                }
+                match (operand, argument.kind) {
+                    (Operand::Register, ArgumentKind::RegisterPair) => {
+                        return Err(self.error(argument.span, format!("expected a single register, not a pair, {}", expected)));
+                    },
+                    (Operand::RegisterPair, ArgumentKind::Register) => {
+                        return Err(self.error(argument.span, format!("expected a register pair like `r1, r5`, {}", expected)));
+                    },
+                    _ => {},
+                }
+                if let (Operand::Register, Some(register)) = (operand, argument.value) {
+                    if register > 15 {
+                        return Err(self.error(argument.span, format!("there is no register {}, registers go from 0 to 15", register)));
                     }
                 }
+            }
+        }
+        match arguments.next() {
+            Some(extra) => Err(self.error(extra.span, format!("too many operands, {}", expected))),
+            None => Ok(()),
+        }
+    }
 
-                *bytes_count += ((stop - start) + 1);
-                for offset in start..=stop {
-                    unsafe {
-                        data.push(DataObject::Byte(*((&num as *const _ as u64 + offset as u64) as *const u8)));
+    fn resolve_section(&mut self, section: Section) -> Vec<u8> {
+        let mut return_data: Vec<u8> = vec![];
+
+        for obj in section.data {
+            let (value, start, stop) = match obj {
+                DataObject::Byte(x) => {
+                    return_data.push(x);
+                    continue;
+                },
+                DataObject::LabelRequest(name, start, stop, inc, span) => match self.labels.get(&name) {
+                    Some(location) => (location + inc, start, stop),
+                    None => {
+                        self.diagnostics.push(self.unknown_label(&name, span));
+                        (0, start, stop)
+                    },
+                },
+                DataObject::SizeRequest(name, start, stop, span) => match self.sizes.get(&name) {
+                    Some(size) => (*size, start, stop),
+                    None => {
+                        self.diagnostics.push(self.error(span, format!("unknown size id {}", name)));
+                        (0, start, stop)
+                    },
+                },
+                // the value has to fit in the bytes up to stop, written signed or unsigned.
+                DataObject::ExpressionRequest(expression, start, stop, span) => {
+                    let bits = (stop + 1) * 8;
+                    match self.evaluate(&expression, &mut vec![]) {
+                        Ok(value) if value < -(1i128 << (bits - 1)) || value >= 1i128 << bits => {
+                            self.diagnostics.push(self.error(span, format!("the value {} does not fit in {} bits", value, bits)));
+                            (0, start, stop)
+                        },
+                        Ok(value) => (value as u64, start, stop),
+                        Err(diagnostic) => {
+                            self.diagnostics.push(diagnostic);
+                            (0, start, stop)
+                        },
                     }
+                },
+            };
+            return_data.extend_from_slice(&value.to_ne_bytes()[start as usize..=stop as usize]);
+        }
+
+        assert!(return_data.len() == section.bytes_count);
+
+        return_data
+    }
+
+    // resolving holds the constants being worked out so one defined in terms of itself is caught.
+    fn evaluate(&self, expression: &Expression, resolving: &mut Vec<String>) -> ParseResult<i128> {
+        expression.evaluate(self.sources, &mut |reference| match reference {
+            Expression::Label(name, span) => self.labels.get(name).map(|location| *location as i128)
+                .ok_or_else(|| self.unknown_label(name, *span)),
+            Expression::Size(name, span) => self.sizes.get(name).map(|size| *size as i128)
+                .ok_or_else(|| self.error(*span, format!("unknown size id {}", name))),
+            Expression::Constant(name, span) => {
+                let Some((definition, _)) = self.constants.get(name) else {
+                    let suggestion = did_you_mean(name, self.constants.keys().map(String::as_str));
+                    return Err(self.error(*span, format!("unknown constant {}{}", name, suggestion)));
+                };
+                if resolving.contains(name) {
+                    return Err(self.error(*span, format!("constant {} is defined in terms of itself", name)));
                 }
+                resolving.push(name.clone());
+                let value = self.evaluate(definition, resolving);
+                resolving.pop();
+                value
+            },
+            _ => unreachable!(),
+        })
+    }
+
+    // constants that are never used still get their mistakes reported.
+    fn check_constants(&mut self) {
+        for (name, (definition, _)) in &self.constants {
+            if let Err(diagnostic) = self.evaluate(definition, &mut vec![name.clone()]) {
+                self.diagnostics.push(diagnostic);
             }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            Whitespace(_) => unreachable!(),
-            token => panic!("unexpected token {:?}", token),
+    fn assemble(source: &str, defines: &[(&str, i128)]) -> Result<ArsenalObject, Vec<Diagnostic>> {
+        let defines: Vec<(String, i128)> = defines.iter().map(|(name, value)| (name.to_string(), *value)).collect();
+        parse_files(vec![("test.ars".to_string(), source.as_bytes().to_vec())], &defines)
+    }
+
+    // the messages with the line and column they point at.
+    fn errors(source: &str, defines: &[(&str, i128)]) -> Vec<(String, usize, usize)> {
+        match assemble(source, defines) {
+            Ok(_) => vec![],
+            Err(diagnostics) => diagnostics.into_iter().map(|diagnostic| (diagnostic.message, diagnostic.line, diagnostic.column)).collect(),
+        }
+    }
+
+    #[test]
+    fn a_define_conflicts_with_an_equ_of_the_same_name() {
+        let source = "Halt;\n.equ LEVEL = 2;\n";
+        assert_eq!(errors(source, &[]), []);
+        assert_eq!(errors(source, &[("LEVEL", 1)]), [("constant LEVEL is already defined".to_string(), 2, 6)]);
+        assert_eq!(errors(source, &[("OTHER", 1)]), []);
+    }
+
+    #[test]
+    fn a_define_can_stand_in_for_a_guarded_equ() {
+        let source = ".ifndef LEVEL;\n.equ LEVEL = 0;\n.endif;\nmov.q r1, LEVEL;\nHalt;\n";
+        assert_eq!(errors(source, &[("LEVEL", 3)]), []);
+        assert_eq!(errors(source, &[]), []);
+    }
+
+    // the program bytes, labels are stored in single bytes with &name:0->0 to keep the expected data short.
+    fn data(source: &str) -> Vec<u8> {
+        match assemble(source, &[]) {
+            Ok(ArsenalObject::ArsenalCompiledObject { data, .. }) => data,
+            other => panic!("expected a program, got {:?}", other),
         }
     }
+
+    #[test]
+    fn a_label_can_only_be_defined_once() {
+        let source = "label start:\nHalt;\nlabel start:\nHalt;\n";
+        assert_eq!(errors(source, &[]), [("label start is already defined at test.ars:1".to_string(), 3, 7)]);
+        let source = "label start:\n() start = 0;\n";
+        assert_eq!(errors(source, &[]), [("label start is already defined at test.ars:1".to_string(), 2, 4)]);
+    }
+
+    #[test]
+    fn a_local_label_belongs_to_the_global_above_it() {
+        let source = "
+label first:
+label .loop:
+    () a = &.loop:0->0;
+label second:
+    () pad = 0 0;
+label .loop:
+    () b = &.loop:0->0 &first.loop:0->0 &second.loop:0->0;
+";
+        assert_eq!(data(source), [0, 0, 0, 3, 0, 3]);
+    }
+
+    #[test]
+    fn a_local_label_is_only_unique_under_its_global() {
+        let source = "label first:\nlabel .loop:\nlabel .loop:\n";
+        assert_eq!(errors(source, &[]), [("label .loop is already defined at test.ars:2".to_string(), 3, 7)]);
+        let source = "label .loop:\nHalt;\n";
+        assert_eq!(errors(source, &[]), [("local label .loop needs a global label before it".to_string(), 1, 7)]);
+    }
+
+    #[test]
+    fn anonymous_labels_point_forward_and_backward() {
+        let source = "
+label 1:
+    () a = &1f:0->0 &1b:0->0;
+label 1:
+    () b = &1b:0->0 &1f:0->0;
+label 1:
+    () c = 9;
+";
+        assert_eq!(data(source), [2, 0, 2, 4, 9]);
+    }
+
+    #[test]
+    fn anonymous_references_need_a_label_to_point_at() {
+        let source = "() a = &1b:0->0;\nlabel 1:\n() b = &1f:0->0;\n";
+        let messages: Vec<String> = errors(source, &[]).into_iter().map(|(message, _, _)| message).collect();
+        assert_eq!(messages, ["there is no `label 1:` before 1b", "there is no `label 1:` after 1f"]);
+    }
 }
@@ -0,0 +1,131 @@
+use std::str::FromStr;
+
+use arsenal_globals::Instructions;
+
+// the short forms are written destination first, `add.q r1, 5` adds 5 to r1.
+pub const MNEMONICS: &[&str] = &[
+    "mov", "add", "sub", "and", "or", "xor", "not", "cmp", "push", "pop", "inc", "dec",
+    "jmp", "je", "jne", "jg", "jng", "jl", "jnl", "jz", "jnz", "hlt", "nop", "iret",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shape {
+    Register,
+    Immediate,
+    // [r2], the memory a register points at.
+    AddressedRegister,
+    // [&label], memory at a fixed address.
+    Memory,
+}
+
+impl Shape {
+    fn name(self) -> &'static str {
+        match self {
+            Shape::Register => "a register",
+            Shape::Immediate => "an immediate",
+            Shape::AddressedRegister => "a [register]",
+            Shape::Memory => "a [address]",
+        }
+    }
+}
+
+// which written operands fill each operand of the chosen instruction, a pair is packed high nibble first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    Pair(usize, usize),
+    Operand(usize),
+}
+
+fn width_name(suffix: &str) -> Option<&'static str> {
+    match suffix {
+        "b" => Some("Byte"),
+        "w" => Some("Short"),
+        "d" => Some("Int"),
+        "q" => Some("Long"),
+        _ => None,
+    }
+}
+
+pub fn is_width_suffix(suffix: &str) -> bool {
+    width_name(suffix).is_some()
+}
+
+fn jump(mnemonic: &str) -> Option<Instructions> {
+    use Instructions::*;
+    Some(match mnemonic {
+        "jmp" => JumpTo,
+        "je" => JumpIfEqualTo,
+        "jne" => JumpIfNotEqualTo,
+        "jg" => JumpIfGreaterThan,
+        "jng" => JumpIfNotGreaterThan,
+        "jl" => JumpIfLessThan,
+        "jnl" => JumpIfNotLessThan,
+        "jz" => JumpIfZero,
+        "jnz" => JumpIfNotZero,
+        _ => return None,
+    })
+}
+
+fn arithmetic(mnemonic: &str) -> Option<&'static str> {
+    match mnemonic {
+        "add" => Some("Add"),
+        "sub" => Some("Subtract"),
+        "and" => Some("BitwiseAnd"),
+        "or" => Some("BitwiseOr"),
+        "xor" => Some("BitwiseXOr"),
+        _ => None,
+    }
+}
+
+// picks the instruction a short form stands for from its width suffix and the kinds of operands it was given.
+pub fn select(mnemonic: &str, suffix: Option<&str>, shapes: &[Shape]) -> Result<(Instructions, Vec<Slot>), String> {
+    use Shape::*;
+    use Slot::*;
+
+    let sized = |base: &str| match suffix.and_then(width_name) {
+        Some(width) => Ok(Instructions::from_str(&format!("{}{}", base, width)).expect("every sized instruction has all four widths")),
+        None => Err(format!("`{}` needs a width suffix, one of .b .w .d or .q", mnemonic)),
+    };
+    let widthless = |instruction: Instructions| match suffix {
+        Some(suffix) => Err(format!("`{}` does not take a width suffix, found .{}", mnemonic, suffix)),
+        None => Ok(instruction),
+    };
+
+    let (instruction, slots) = match (mnemonic, shapes) {
+        ("mov", [Register, Register]) => (sized("MoveRegisters")?, vec![Pair(1, 0)]),
+        ("mov", [Register, Immediate]) => (sized("LoadRegister")?, vec![Operand(0), Operand(1)]),
+        ("mov", [Register, AddressedRegister]) => (sized("MoveAddressedRegisterRegister")?, vec![Pair(1, 0)]),
+        ("mov", [AddressedRegister, Register]) => (sized("MoveRegisterAddressedRegister")?, vec![Pair(1, 0)]),
+        ("mov", [AddressedRegister, AddressedRegister]) => (sized("MoveAddressedRegisters")?, vec![Pair(1, 0)]),
+        ("mov", [Register, Memory]) => (sized("MoveMemoryRegister")?, vec![Operand(1), Operand(0)]),
+        ("mov", [Memory, Register]) => (sized("MoveRegisterMemory")?, vec![Operand(1), Operand(0)]),
+        (operation, [Register, Register]) if arithmetic(operation).is_some() => {
+            (sized(&format!("{}Registers", arithmetic(operation).unwrap()))?, vec![Pair(1, 0)])
+        },
+        (operation, [Register, Immediate]) if arithmetic(operation).is_some() => {
+            (sized(&format!("{}RegisterImmediate", arithmetic(operation).unwrap()))?, vec![Operand(0), Operand(1)])
+        },
+        ("not", [Register]) => (sized("BitwiseNotRegister")?, vec![Operand(0)]),
+        // compares left against right, so jg after `cmp r0, r1` jumps when r0 is greater.
+        ("cmp", [Register, Register]) => (sized("CompareRegister")?, vec![Pair(0, 1)]),
+        ("cmp", [Register, Immediate]) => (sized("CompareRegisterLiteral")?, vec![Operand(0), Operand(1)]),
+        ("push", [Register]) => (sized("PushRegister")?, vec![Operand(0)]),
+        ("push", [Memory]) => (sized("PushMemory")?, vec![Operand(0)]),
+        ("pop", [Register]) => (sized("PopRegister")?, vec![Operand(0)]),
+        ("pop", [Memory]) => (sized("PopMemory")?, vec![Operand(0)]),
+        ("inc", [Register]) => (widthless(Instructions::IncrementRegister)?, vec![Operand(0)]),
+        ("dec", [Register]) => (widthless(Instructions::DecrementRegister)?, vec![Operand(0)]),
+        (operation, [Immediate]) if jump(operation).is_some() => (widthless(jump(operation).unwrap())?, vec![Operand(0)]),
+        ("hlt", []) => (widthless(Instructions::Halt)?, vec![]),
+        ("hlt", [Register]) => (widthless(Instructions::HaltRegister)?, vec![Operand(0)]),
+        ("hlt", [Immediate]) => (widthless(Instructions::HaltImmediate)?, vec![Operand(0)]),
+        ("nop", []) => (widthless(Instructions::NoOperation)?, vec![]),
+        ("iret", []) => (widthless(Instructions::InterruptReturn)?, vec![]),
+        _ => {
+            let shapes: Vec<&str> = shapes.iter().map(|shape| shape.name()).collect();
+            let given = if shapes.is_empty() { "no operands".to_string() } else { shapes.join(", ") };
+            return Err(format!("`{}` cannot be used with {}", mnemonic, given));
+        },
+    };
+    Ok((instruction, slots))
+}
@@ -0,0 +1,687 @@
+use std::{collections::HashMap, path::{Path, PathBuf}, str::FromStr};
+
+use arsenal_globals::{Instructions, register_index};
+
+use crate::diagnostic::{Diagnostic, Source, SourceMap, did_you_mean};
+use crate::expression::{self, Expression};
+use crate::mnemonics::MNEMONICS;
+use crate::tokenizer::{self, ArsenalToken, Span, Spanned};
+
+// a macro that keeps calling itself is stopped here instead of expanding forever.
+const MAX_DEPTH: usize = 64;
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Spanned<ArsenalToken>>,
+    // labels, data blocks and sizes defined in the body, renamed on every expansion so the macro can be used twice.
+    locals: Vec<String>,
+}
+
+// one .if up to its .endif, active while the lines are being assembled.
+struct Conditional {
+    span: Span,
+    // everything inside is skipped when the surrounding lines are.
+    enclosing_active: bool,
+    active: bool,
+    // a branch was already taken, so the later ones are skipped.
+    taken: bool,
+    seen_else: bool,
+}
+
+// reads the files, follows .include and expands macros, what is left is plain statements for the parser.
+// every token keeps the span it was written at, in its file, in a macro body or in the arguments of a call.
+pub struct Preprocessor<'a> {
+    sources: &'a mut SourceMap,
+    diagnostics: &'a mut Vec<Diagnostic>,
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    // the files being read right now, outermost first, to catch a file that ends up including itself.
+    including: Vec<(PathBuf, String)>,
+    output: Vec<Spanned<ArsenalToken>>,
+    // .equ and .const seen so far and the -D values, for .if and .ifdef.
+    constants: HashMap<String, Expression>,
+}
+
+impl<'a> Preprocessor<'a> {
+    pub fn new(sources: &'a mut SourceMap, diagnostics: &'a mut Vec<Diagnostic>) -> Self {
+        Preprocessor { sources, diagnostics, macros: HashMap::new(), expansions: 0, including: vec![], output: vec![], constants: HashMap::new() }
+    }
+
+    // a constant given from outside the files, like -D on the command line.
+    pub fn define_constant(&mut self, name: &str, value: i128) {
+        self.constants.insert(name.to_string(), Expression::Number(value));
+    }
+
+    // files are read one after the other as if each was included at the end of the one before it.
+    pub fn file(&mut self, name: &str, data: Vec<u8>) {
+        let mut output = std::mem::take(&mut self.output);
+        self.read(name, data, None, 0, &mut output);
+        self.output = output;
+    }
+
+    // the statements of all files read so far and the names of the macros they defined.
+    pub fn finish(self) -> (Vec<Spanned<ArsenalToken>>, Vec<String>) {
+        (self.output, self.macros.into_keys().collect())
+    }
+}
+
+// the index just past the next ;, or the end when there is none.
+fn statement_end(tokens: &[Spanned<ArsenalToken>], position: usize) -> usize {
+    tokens[position..].iter().position(|token| matches!(token.token, ArsenalToken::LineEnd(_)))
+        .map_or(tokens.len(), |index| position + index + 1)
+}
+
+fn is_directive(token: &ArsenalToken, name: &str) -> bool {
+    matches!(token, ArsenalToken::SpecialIdentifier(directive) if directive == name)
+}
+
+impl Preprocessor<'_> {
+    fn expand(&mut self, tokens: &[Spanned<ArsenalToken>], depth: usize, output: &mut Vec<Spanned<ArsenalToken>>) {
+        use ArsenalToken::*;
+        let mut position = 0;
+        // macros and directives are only recognized where a statement starts, after a ; or a label.
+        let mut at_start = true;
+        // conditionals end with the file or macro body they are written in.
+        let mut conditionals: Vec<Conditional> = vec![];
+        while let Some(token) = tokens.get(position) {
+            let active = conditionals.last().is_none_or(|conditional| conditional.active);
+            if at_start {
+                if let SpecialIdentifier(directive) = &token.token {
+                    if matches!(directive.as_str(), ".if" | ".ifdef" | ".ifndef" | ".elif" | ".else" | ".endif") {
+                        position = self.conditional(tokens, position, &mut conditionals);
+                        continue;
+                    }
+                    if active && matches!(directive.as_str(), ".equ" | ".const") {
+                        self.record_constant(&tokens[position + 1..]);
+                    }
+                }
+                match &token.token {
+                    _ if !active => {},
+                    SpecialIdentifier(directive) if directive == ".macro" => {
+                        position = self.define(tokens, position);
+                        continue;
+                    },
+                    SpecialIdentifier(directive) if directive == ".include" => {
+                        position = self.include(tokens, position, depth, output);
+                        continue;
+                    },
+                    SpecialIdentifier(directive) if directive == ".endm" => {
+                        self.diagnostics.push(self.sources.error(token.span, ".endm without a .macro to close"));
+                        position = statement_end(tokens, position);
+                        continue;
+                    },
+                    Identifier(name) if self.macros.contains_key(name) => {
+                        position = self.call(tokens, position, depth, output);
+                        continue;
+                    },
+                    _ => {},
+                }
+            }
+            at_start = match token.token {
+                LineEnd(_) => true,
+                Selection(_) => position >= 2 && matches!(tokens[position - 2].token, Label(_)),
+                _ => false,
+            };
+            if active {
+                output.push(token.clone());
+            }
+            position += 1;
+        }
+        for unclosed in conditionals {
+            self.diagnostics.push(self.sources.error(unclosed.span, "missing .endif for this conditional"));
+        }
+    }
+
+    // handles one of the conditional directives and returns the index after its ;.
+    fn conditional(&mut self, tokens: &[Spanned<ArsenalToken>], start: usize, conditionals: &mut Vec<Conditional>) -> usize {
+        let end = statement_end(tokens, start);
+        let ArsenalToken::SpecialIdentifier(directive) = &tokens[start].token else { unreachable!() };
+        let span = tokens[start].span;
+        let arguments = &tokens[start + 1..end];
+        if !matches!(arguments.last(), Some(Spanned { token: ArsenalToken::LineEnd(_), .. })) {
+            self.diagnostics.push(self.sources.error(span, format!("expected `;` to end {}", directive)));
+            return end;
+        }
+        let arguments = &arguments[..arguments.len() - 1];
+
+        if let ".if" | ".ifdef" | ".ifndef" = directive.as_str() {
+            let enclosing_active = conditionals.last().is_none_or(|conditional| conditional.active);
+            let condition = enclosing_active && self.condition(directive, span, arguments);
+            conditionals.push(Conditional { span, enclosing_active, active: condition, taken: condition, seen_else: false });
+            return end;
+        }
+
+        let Some(conditional) = conditionals.last_mut() else {
+            self.diagnostics.push(self.sources.error(span, format!("{} without an .if", directive)));
+            return end;
+        };
+        if directive != ".elif" && !arguments.is_empty() {
+            self.diagnostics.push(self.sources.error(arguments[0].span, format!("expected `;` after {}", directive)));
+        }
+        match directive.as_str() {
+            ".elif" | ".else" if conditional.seen_else => {
+                self.diagnostics.push(self.sources.error(span, format!("{} after the .else of this conditional", directive)));
+            },
+            // a later condition is only looked at when no branch before it was taken.
+            ".elif" => {
+                let condition = conditional.enclosing_active && !conditional.taken && self.condition(directive, span, arguments);
+                conditional.active = condition;
+                conditional.taken |= condition;
+            },
+            ".else" => {
+                conditional.active = conditional.enclosing_active && !conditional.taken;
+                conditional.taken = true;
+                conditional.seen_else = true;
+            },
+            _ => { conditionals.pop(); },
+        }
+        end
+    }
+
+    // a broken condition is reported and counts as false.
+    fn condition(&mut self, directive: &str, span: Span, arguments: &[Spanned<ArsenalToken>]) -> bool {
+        use ArsenalToken::*;
+        let result = match (directive, arguments) {
+            (".ifdef", [Spanned { token: Identifier(name), .. }]) => Ok(self.constants.contains_key(name)),
+            (".ifndef", [Spanned { token: Identifier(name), .. }]) => Ok(!self.constants.contains_key(name)),
+            (".ifdef" | ".ifndef", _) => Err(self.sources.error(span, format!("expected a constant name and `;` after {}", directive))),
+            (_, []) => Err(self.sources.error(span, format!("expected a condition after {}", directive))),
+            _ => {
+                let mut position = 0;
+                expression::parse(self.sources, arguments, &mut position).and_then(|(condition, _)| match arguments.get(position) {
+                    Some(extra) => Err(self.sources.error(extra.span, format!("expected an operator or `;`, found `{}`", extra.token.text()))),
+                    None => self.evaluate(&condition, &mut vec![]).map(|value| value != 0),
+                })
+            },
+        };
+        result.unwrap_or_else(|diagnostic| {
+            self.diagnostics.push(diagnostic);
+            false
+        })
+    }
+
+    // remembers `NAME = expression` after .equ or .const for later conditions, the parser reports any mistakes in it.
+    fn record_constant(&mut self, rest: &[Spanned<ArsenalToken>]) {
+        if let [Spanned { token: ArsenalToken::Identifier(name), .. }, Spanned { token: ArsenalToken::VarAssignment(_), .. }, ..] = rest {
+            let mut position = 2;
+            if let Ok((expression, _)) = expression::parse(self.sources, rest, &mut position) {
+                self.constants.entry(name.clone()).or_insert(expression);
+            }
+        }
+    }
+
+    // conditions are worked out before anything is assembled, so they can only use constants defined above them.
+    fn evaluate(&self, expression: &Expression, resolving: &mut Vec<String>) -> Result<i128, Diagnostic> {
+        expression.evaluate(self.sources, &mut |reference| match reference {
+            Expression::Constant(name, span) => {
+                let Some(definition) = self.constants.get(name) else {
+                    let suggestion = did_you_mean(name, self.constants.keys().map(String::as_str));
+                    return Err(self.sources.error(*span, format!("unknown constant {}{}", name, suggestion)));
+                };
+                if resolving.contains(name) {
+                    return Err(self.sources.error(*span, format!("constant {} is defined in terms of itself", name)));
+                }
+                resolving.push(name.clone());
+                let value = self.evaluate(definition, resolving);
+                resolving.pop();
+                value
+            },
+            Expression::Label(name, span) | Expression::Size(name, span) => {
+                Err(self.sources.error(*span, format!("{} is not known yet, conditions can only use constants", name)))
+            },
+            _ => unreachable!(),
+        })
+    }
+
+    fn read(&mut self, name: &str, data: Vec<u8>, included_from: Option<Span>, depth: usize, output: &mut Vec<Spanned<ArsenalToken>>) {
+        let (text, invalid_at) = match String::from_utf8(data) {
+            Ok(text) => (text, None),
+            Err(error) => {
+                let at = error.utf8_error().valid_up_to();
+                (String::from_utf8_lossy(error.as_bytes()).into_owned(), Some(at))
+            },
+        };
+        let id = self.sources.add(Source { name: name.to_string(), text, included_from });
+        if let Some(at) = invalid_at {
+            self.diagnostics.push(self.sources.error(Span { source: id, start: at, end: at }, "source is not valid utf-8"));
+            return;
+        }
+
+        let (tokens, unrecognized) = tokenizer::tokenize::<ArsenalToken>(&self.sources.get(id).text, id);
+        for span in unrecognized {
+            let diagnostic = self.sources.error(span, format!("unrecognized input `{}`", self.sources.text(span)));
+            self.diagnostics.push(diagnostic);
+        }
+        let canonical = Path::new(name).canonicalize().unwrap_or_else(|_| PathBuf::from(name));
+        self.including.push((canonical, name.to_string()));
+        self.expand(&tokens, depth, output);
+        self.including.pop();
+    }
+
+    // reads `.include "path";` and expands the file in its place, the path is relative to the including file.
+    fn include(&mut self, tokens: &[Spanned<ArsenalToken>], start: usize, depth: usize, output: &mut Vec<Spanned<ArsenalToken>>) -> usize {
+        use ArsenalToken::*;
+        let end = statement_end(tokens, start);
+        let directive = tokens[start].span;
+        let (path, path_span) = match &tokens[start + 1..end] {
+            [Spanned { token: StringLiteral(path), span }, Spanned { token: LineEnd(_), .. }] => (&path[1..path.len() - 1], *span),
+            [Spanned { token: StringLiteral(_), span }, ..] => {
+                self.diagnostics.push(self.sources.error(*span, "expected `;` after the path of .include"));
+                return end;
+            },
+            _ => {
+                self.diagnostics.push(self.sources.error(directive, "expected a quoted path after .include"));
+                return end;
+            },
+        };
+
+        let including = Path::new(&self.sources.get(directive.source).name);
+        let path = including.parent().unwrap_or(Path::new("")).join(path);
+        let name = path.display().to_string();
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+        if let Some(index) = self.including.iter().position(|(file, _)| *file == canonical) {
+            let cycle: Vec<&str> = self.including[index..].iter().map(|(_, name)| name.as_str()).chain([name.as_str()]).collect();
+            self.diagnostics.push(self.sources.error(path_span, format!("include cycle, {}", cycle.join(" includes "))));
+            return end;
+        }
+        if depth == MAX_DEPTH {
+            self.diagnostics.push(self.sources.error(path_span, format!("includes and macros are nested more than {} deep", MAX_DEPTH)));
+            return end;
+        }
+        match std::fs::read(&path) {
+            Ok(data) => self.read(&name, data, Some(directive), depth + 1, output),
+            Err(error) => self.diagnostics.push(self.sources.error(path_span, format!("could not read {}: {}", name, error))),
+        }
+        end
+    }
+
+    // reads `.macro name params;` up to the matching `.endm;` and returns the index after it.
+    // a broken header still skips the whole body so it is not assembled as plain code.
+    fn define(&mut self, tokens: &[Spanned<ArsenalToken>], start: usize) -> usize {
+        let header_end = statement_end(tokens, start);
+        let mut nesting = 0;
+        let Some(endm) = (header_end..tokens.len()).find(|&index| {
+            if is_directive(&tokens[index].token, ".macro") {
+                nesting += 1;
+            } else if is_directive(&tokens[index].token, ".endm") {
+                if nesting == 0 {
+                    return true;
+                }
+                nesting -= 1;
+            }
+            false
+        }) else {
+            self.diagnostics.push(self.sources.error(tokens[start].span, ".macro without a matching .endm"));
+            return tokens.len();
+        };
+
+        let end = match tokens.get(endm + 1) {
+            Some(Spanned { token: ArsenalToken::LineEnd(_), .. }) => endm + 2,
+            _ => {
+                self.diagnostics.push(self.sources.error(tokens[endm].span, "expected `;` after .endm"));
+                endm + 1
+            },
+        };
+
+        match self.header(&tokens[start..header_end]) {
+            Ok((name, params)) => {
+                let body = tokens[header_end..endm].to_vec();
+                let locals = defined_names(&body);
+                self.macros.insert(name, Macro { params, body, locals });
+            },
+            Err(diagnostic) => self.diagnostics.push(diagnostic),
+        }
+        end
+    }
+
+    fn header(&self, header: &[Spanned<ArsenalToken>]) -> Result<(String, Vec<String>), Diagnostic> {
+        use ArsenalToken::*;
+        let directive = &header[0];
+        let (name, name_span) = match header.get(1) {
+            Some(Spanned { token: Identifier(name), span }) => (name, *span),
+            Some(found) => return Err(self.sources.error(found.span, format!("expected a macro name after .macro, found `{}`", found.token.text()))),
+            None => return Err(self.sources.error(directive.span, "expected a macro name after .macro")),
+        };
+        if Instructions::from_str(name).is_ok() || MNEMONICS.contains(&name.as_str()) {
+            return Err(self.sources.error(name_span, format!("{} is already an instruction", name)));
+        }
+        if register_index(name).is_some() {
+            return Err(self.sources.error(name_span, format!("{} is a register name", name)));
+        }
+        if self.macros.contains_key(name) {
+            return Err(self.sources.error(name_span, format!("macro {} is already defined", name)));
+        }
+
+        // parameters are separated by commas like the arguments of a call.
+        let mut params: Vec<String> = vec![];
+        let mut rest = header[2..].iter();
+        let unexpected = |found: Option<&Spanned<ArsenalToken>>, expected: &str| match found {
+            Some(found) => self.sources.error(found.span, format!("expected {} in the .macro header, found `{}`", expected, found.token.text())),
+            None => self.sources.error(header.last().unwrap().span, format!("expected {} in the .macro header", expected)),
+        };
+        loop {
+            match rest.next() {
+                Some(Spanned { token: LineEnd(_), .. }) if params.is_empty() => return Ok((name.clone(), params)),
+                Some(Spanned { token: Identifier(param), span }) => {
+                    if register_index(param).is_some() {
+                        return Err(self.sources.error(*span, format!("{} is a register name and cannot be a parameter", param)));
+                    }
+                    if params.contains(param) {
+                        return Err(self.sources.error(*span, format!("parameter {} is declared twice", param)));
+                    }
+                    params.push(param.clone());
+                },
+                found => return Err(unexpected(found, "a parameter name")),
+            }
+            match rest.next() {
+                Some(Spanned { token: LineEnd(_), .. }) => return Ok((name.clone(), params)),
+                Some(Spanned { token: Separator(_), .. }) => {},
+                found => return Err(unexpected(found, "`,` or `;`")),
+            }
+        }
+    }
+
+    // expands `name arg, arg;` in place and returns the index after its ;.
+    fn call(&mut self, tokens: &[Spanned<ArsenalToken>], start: usize, depth: usize, output: &mut Vec<Spanned<ArsenalToken>>) -> usize {
+        let end = statement_end(tokens, start);
+        let ArsenalToken::Identifier(name) = &tokens[start].token else { unreachable!() };
+        let call_span = tokens[start].span.to(tokens[end - 1].span);
+        if !matches!(tokens[end - 1].token, ArsenalToken::LineEnd(_)) {
+            self.diagnostics.push(self.sources.error(call_span, format!("expected `;` after the arguments of {}", name)));
+            return end;
+        }
+
+        let inner = &tokens[start + 1..end - 1];
+        let arguments: Vec<&[Spanned<ArsenalToken>]> = if inner.is_empty() {
+            vec![]
+        } else {
+            inner.split(|token| matches!(token.token, ArsenalToken::Separator(_))).collect()
+        };
+
+        let definition = &self.macros[name];
+        if arguments.len() != definition.params.len() {
+            let message = format!("macro {} takes {} argument(s), found {}", name, definition.params.len(), arguments.len());
+            self.diagnostics.push(self.sources.error(call_span, message));
+            return end;
+        }
+        if arguments.iter().any(|argument| argument.is_empty()) {
+            self.diagnostics.push(self.sources.error(call_span, format!("empty argument in the call to {}", name)));
+            return end;
+        }
+        if depth == MAX_DEPTH {
+            let message = format!("macro {} is nested more than {} calls deep, does it call itself?", name, MAX_DEPTH);
+            self.diagnostics.push(self.sources.error(call_span, message));
+            return end;
+        }
+
+        self.expansions += 1;
+        let mut body = vec![];
+        for token in &definition.body {
+            match &token.token {
+                ArsenalToken::Identifier(word) => {
+                    if let Some(index) = definition.params.iter().position(|param| param == word) {
+                        body.extend_from_slice(arguments[index]);
+                        continue;
+                    }
+                    if definition.locals.contains(word) {
+                        let renamed = format!("__{}_{}_{}", name, self.expansions, word);
+                        body.push(Spanned { token: ArsenalToken::Identifier(renamed), span: token.span });
+                        continue;
+                    }
+                    body.push(token.clone());
+                },
+                _ => body.push(token.clone()),
+            }
+        }
+        self.expand(&body, depth + 1, output);
+        end
+    }
+}
+
+// the names a body defines with `label name:` or `(size) name =`.
+fn defined_names(body: &[Spanned<ArsenalToken>]) -> Vec<String> {
+    use ArsenalToken::*;
+    let mut names = vec![];
+    for window in body.windows(2) {
+        if let [Spanned { token: Label(_), .. }, Spanned { token: Identifier(name), .. }] = window {
+            names.push(name.clone());
+        }
+    }
+    for window in body.windows(4) {
+        match window {
+            [Spanned { token: OpenParen(_), .. }, Spanned { token: ClosedParen(_), .. }, Spanned { token: Identifier(name), .. }, Spanned { token: VarAssignment(_), .. }] => {
+                names.push(name.clone());
+            },
+            [Spanned { token: OpenParen(_), .. }, Spanned { token: Identifier(size), .. }, Spanned { token: ClosedParen(_), .. }, Spanned { token: Identifier(name), .. }] => {
+                names.push(size.clone());
+                names.push(name.clone());
+            },
+            _ => {},
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the statements left after preprocessing, one token text after the other, and the error messages.
+    fn preprocess(source: &str) -> (String, Vec<String>) {
+        preprocess_with(source, &[])
+    }
+
+    // defines are given like -D on the command line.
+    fn preprocess_with(source: &str, defines: &[(&str, i128)]) -> (String, Vec<String>) {
+        let mut sources = SourceMap::default();
+        let mut diagnostics = vec![];
+        let mut preprocessor = Preprocessor::new(&mut sources, &mut diagnostics);
+        for (name, value) in defines {
+            preprocessor.define_constant(name, *value);
+        }
+        preprocessor.file("test.ars", source.as_bytes().to_vec());
+        let (tokens, _) = preprocessor.finish();
+        let text: Vec<&str> = tokens.iter().map(|token| token.token.text()).collect();
+        (text.join(" "), diagnostics.into_iter().map(|diagnostic| diagnostic.message).collect())
+    }
+
+    // writes the files into a fresh directory and preprocesses the first one, tests run in parallel.
+    fn preprocess_files(test: &str, files: &[(&str, &str)]) -> (PathBuf, String, Vec<Diagnostic>) {
+        let directory = std::env::temp_dir().join(format!("arsenal-include-{}-{test}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        for (name, text) in files {
+            let path = directory.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text).unwrap();
+        }
+        let main = directory.join(files[0].0).display().to_string();
+        let mut sources = SourceMap::default();
+        let mut diagnostics = vec![];
+        let mut preprocessor = Preprocessor::new(&mut sources, &mut diagnostics);
+        preprocessor.file(&main, files[0].1.as_bytes().to_vec());
+        let (tokens, _) = preprocessor.finish();
+        let text: Vec<&str> = tokens.iter().map(|token| token.token.text()).collect();
+        (directory, text.join(" "), diagnostics)
+    }
+
+    #[test]
+    fn parameters_are_replaced_by_their_arguments() {
+        let (output, errors) = preprocess(".macro load reg, value;\n    LoadRegisterLong reg value;\n.endm;\nload r1, #5:0->3;\nload r2, &text;\n");
+        assert_eq!(output, "LoadRegisterLong r1 # 5 : 0 -> 3 ; LoadRegisterLong r2 & text ;");
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn wrong_argument_count_is_an_error() {
+        let (output, errors) = preprocess(".macro load reg, value;\n    LoadRegisterLong reg value;\n.endm;\nload r1;\n");
+        assert_eq!(output, "");
+        assert_eq!(errors, ["macro load takes 2 argument(s), found 1"]);
+    }
+
+    #[test]
+    fn locals_are_renamed_on_every_call() {
+        let source = ".macro wait count;\n    mov.q r2, count;\nlabel loop:\n    dec r2;\n    jnz &loop;\n.endm;\nwait 3;\nwait 4;\n";
+        let (output, errors) = preprocess(source);
+        assert_eq!(output, [
+            "mov .q r2 , 3 ; label __wait_1_loop : dec r2 ; jnz & __wait_1_loop ;",
+            "mov .q r2 , 4 ; label __wait_2_loop : dec r2 ; jnz & __wait_2_loop ;",
+        ].join(" "));
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn data_blocks_and_sizes_are_locals_too() {
+        let (output, _) = preprocess(".macro text;\n    (length) greeting = \"hi\" 0;\n    LoadRegisterLong r0 $length;\n.endm;\ntext;\n");
+        assert_eq!(output, "( __text_1_length ) __text_1_greeting = \"hi\" 0 ; LoadRegisterLong r0 $ __text_1_length ;");
+    }
+
+    #[test]
+    fn macros_can_call_and_define_macros() {
+        let source = ".macro outer;\n    .macro inner;\n        Halt;\n    .endm;\n    Nop;\n    inner;\n.endm;\nouter;\ninner;\n";
+        let (output, errors) = preprocess(source);
+        assert_eq!(output, "Nop ; Halt ; Halt ;");
+        assert!(errors.is_empty());
+    }
+
+    // a chain of macros each calling the next, the last one is called as deep as expansion goes.
+    fn chain(length: usize) -> String {
+        let mut source: String = (0..length - 1).map(|index| format!(".macro m{};\n    m{};\n.endm;\n", index + 1, index + 2)).collect();
+        source += &format!(".macro m{};\n    Halt;\n.endm;\nm1;\n", length);
+        source
+    }
+
+    #[test]
+    fn nesting_up_to_the_limit_expands() {
+        let (output, errors) = preprocess(&chain(MAX_DEPTH));
+        assert_eq!(output, "Halt ;");
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn nesting_past_the_limit_is_an_error() {
+        let (output, errors) = preprocess(&chain(MAX_DEPTH + 1));
+        assert_eq!(output, "");
+        assert_eq!(errors, [format!("macro m{} is nested more than {} calls deep, does it call itself?", MAX_DEPTH + 1, MAX_DEPTH)]);
+    }
+
+    #[test]
+    fn a_macro_calling_itself_stops_at_the_limit() {
+        let (_, errors) = preprocess(".macro forever;\n    Nop;\n    forever;\n.endm;\nforever;\n");
+        assert_eq!(errors, [format!("macro forever is nested more than {} calls deep, does it call itself?", MAX_DEPTH)]);
+    }
+
+    #[test]
+    fn includes_are_relative_to_the_including_file() {
+        let (_, output, errors) = preprocess_files("relative", &[
+            ("main.ars", ".include \"lib/print.ars\";\nHalt;\n"),
+            ("lib/print.ars", ".include \"helpers.ars\";\nNop;\n"),
+            ("lib/helpers.ars", "SysCall PrintCString;\n"),
+        ]);
+        assert_eq!(output, "SysCall PrintCString ; Nop ; Halt ;");
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn a_file_including_itself_is_a_cycle() {
+        let (directory, output, errors) = preprocess_files("direct", &[
+            ("main.ars", "Nop;\n.include \"main.ars\";\n"),
+        ]);
+        let main = directory.join("main.ars").display().to_string();
+        assert_eq!(output, "Nop ;");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, format!("include cycle, {main} includes {main}"));
+        assert_eq!((errors[0].file.as_str(), errors[0].line, errors[0].column), (main.as_str(), 2, 10));
+    }
+
+    #[test]
+    fn a_cycle_through_other_files_names_every_step() {
+        let (directory, _, errors) = preprocess_files("indirect", &[
+            ("main.ars", ".include \"a.ars\";\n"),
+            ("a.ars", "\n.include \"b.ars\";\n"),
+            ("b.ars", ".include \"a.ars\";\n"),
+        ]);
+        let path = |name: &str| directory.join(name).display().to_string();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, format!("include cycle, {} includes {} includes {}", path("a.ars"), path("b.ars"), path("a.ars")));
+        assert_eq!((errors[0].file.clone(), errors[0].line, errors[0].column), (path("b.ars"), 1, 10));
+        assert_eq!(errors[0].included_from, [format!("{}:2", path("a.ars")), format!("{}:1", path("main.ars"))]);
+    }
+
+    #[test]
+    fn a_missing_include_points_at_its_path() {
+        let (directory, _, errors) = preprocess_files("missing", &[
+            ("main.ars", "Nop;\n    .include \"nowhere.ars\";\n"),
+        ]);
+        let (main, missing) = (directory.join("main.ars").display().to_string(), directory.join("nowhere.ars").display().to_string());
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.starts_with(&format!("could not read {missing}: ")), "{}", errors[0].message);
+        assert_eq!((errors[0].file.as_str(), errors[0].line, errors[0].column), (main.as_str(), 2, 14));
+        assert_eq!(errors[0].width, "\"nowhere.ars\"".len());
+    }
+
+    const NESTED: &str = "
+.if LEVEL > 0;
+    .ifdef VERBOSE;
+        NoOperation;
+    .else;
+        Halt;
+    .endif;
+.elif LEVEL == 0;
+    .if 1;
+        InterruptReturn;
+    .endif;
+.else;
+    .if 1;
+        NoOperation;
+    .else;
+        Halt;
+    .endif;
+.endif;
+";
+
+    #[test]
+    fn nested_conditionals_pick_one_branch_each() {
+        assert_eq!(preprocess_with(NESTED, &[("LEVEL", 1), ("VERBOSE", 1)]), ("NoOperation ;".to_string(), vec![]));
+        assert_eq!(preprocess_with(NESTED, &[("LEVEL", 1)]), ("Halt ;".to_string(), vec![]));
+        assert_eq!(preprocess_with(NESTED, &[("LEVEL", 0)]), ("InterruptReturn ;".to_string(), vec![]));
+        assert_eq!(preprocess_with(NESTED, &[("LEVEL", -1), ("VERBOSE", 1)]), ("NoOperation ;".to_string(), vec![]));
+    }
+
+    #[test]
+    fn skipped_branches_are_not_evaluated() {
+        let (output, errors) = preprocess(".if 0;\n    .if MISSING;\n    .endif;\n.else;\n    NoOperation;\n.endif;\n");
+        assert_eq!(output, "NoOperation ;");
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn constants_above_a_condition_can_be_used_in_it() {
+        let (output, errors) = preprocess(".equ LEVEL = 2;\n.if LEVEL * 2 == 4;\n    NoOperation;\n.endif;\n");
+        assert_eq!(output, ".equ LEVEL = 2 ; NoOperation ;");
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn an_unterminated_if_is_an_error() {
+        let (_, errors) = preprocess(".if 1;\n    NoOperation;\n");
+        assert_eq!(errors, ["missing .endif for this conditional"]);
+        let (_, errors) = preprocess(".macro open;\n    .if 1;\n.endm;\nopen;\n.endif;\n");
+        assert_eq!(errors, ["missing .endif for this conditional", ".endif without an .if"]);
+    }
+
+    #[test]
+    fn else_and_elif_are_checked() {
+        let (_, errors) = preprocess(".else;\n");
+        assert_eq!(errors, [".else without an .if"]);
+        let (_, errors) = preprocess(".if 1;\n.else;\n.elif 1;\n.endif;\n");
+        assert_eq!(errors, [".elif after the .else of this conditional"]);
+    }
+
+    #[test]
+    fn defines_are_seen_by_ifdef() {
+        let source = ".ifdef DEBUG;\n    NoOperation;\n.else;\n    Halt;\n.endif;\n";
+        assert_eq!(preprocess_with(source, &[("DEBUG", 1)]).0, "NoOperation ;");
+        assert_eq!(preprocess_with(source, &[("DEBUG", 0)]).0, "NoOperation ;");
+        assert_eq!(preprocess(source).0, "Halt ;");
+    }
+}
@@ -19,8 +19,37 @@ pub enum ArsenalToken {
     VarAssignment(String),
     OpenParen(String),
     ClosedParen(String),
+    OpenBracket(String),
+    ClosedBracket(String),
     Comment(String),
     NumericSlice(String),
+    Operator(String),
+    AnonymousLabel(String),
+}
+
+// byte offsets into one of the source files, end is exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub source: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    // from the start of this span to the end of another, tokens from a macro body or another file keep just this one.
+    pub fn to(self, end: Span) -> Span {
+        if end.source == self.source && end.end >= self.start {
+            Span { end: end.end, ..self }
+        } else {
+            self
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Spanned<T> {
+    pub token: T,
+    pub span: Span,
 }
 
 pub trait Token {
@@ -37,21 +66,27 @@ impl Token for ArsenalToken {
             (",", Separator),
             ("->", Range),
             ("\\b0[xX][0-9A-Fa-f]+\\b", Hex),
+            (r"\d+[fb]\b", AnonymousLabel),
             (r"[-+]?\d+", Number),
-            ("label", Label),
+            ("label\\b", Label),
             ("^\\.[a-zA-Z_][a-zA-Z0-9_]*", SpecialIdentifier),
             ("^[a-zA-Z_][a-zA-Z0-9_]*", Identifier),
             ("&", IDGrab),
             ("\\$", SizeGrab),
             (":", Selection),
+            ("==|!=|<=|>=", Operator),
             ("=>", Shift),
             ("=", VarAssignment),
             ("\\(", OpenParen),
             ("\\)", ClosedParen),
+            ("\\[", OpenBracket),
+            ("\\]", ClosedBracket),
             (r#""(?:\\.|[^\\"])*""#, StringLiteral),
-            (r"//.[^\n]*\n", Comment),
+            (r"//[^\n]*", Comment),
             (r"/\*[^*]*\*+(?:[^/*][^*]*\*+)*/", Comment),
             ("#", NumericSlice),
+            // after the comments so // and /* are never read as a division.
+            (r"<<|>>|[-+*/|~<>]", Operator),
         ] {
             if let Some((pat, length)) = parse_pattern(pattern, data) {
                 return Some((response(pat), &data[length..]));
@@ -69,22 +104,45 @@ impl Token for ArsenalToken {
     }
 }
 
-pub fn tokenize<T: Token + std::fmt::Debug>(input: &str) -> Vec<T> {
-    let mut string = input;
-    let mut ret = vec![];
-    let mut last = ".".to_string();
-    while let Some((next_token, next_string)) = T::next(string) {
-        last = string.clone().to_string();
-        string = next_string;
-        ret.push(next_token);
+impl ArsenalToken {
+    // the source text the token was made from.
+    pub fn text(&self) -> &str {
+        use ArsenalToken::*;
+        match self {
+            Whitespace(text) | LineEnd(text) | Separator(text) | Identifier(text) | Hex(text) | Number(text)
+            | StringLiteral(text) | Label(text) | SpecialIdentifier(text) | IDGrab(text) | SizeGrab(text)
+            | Selection(text) | Range(text) | Shift(text) | VarAssignment(text) | OpenParen(text)
+            | ClosedParen(text) | OpenBracket(text) | ClosedBracket(text) | Comment(text) | NumericSlice(text) | Operator(text)
+            | AnonymousLabel(text) => text,
+        }
     }
-    let mut return_value = vec![];
-    for elm in ret {
-        if !elm.is_whitespace() {
-            return_value.push(elm);
+}
+
+// input that matches no token is skipped and handed back as spans so every bad spot can be reported.
+pub fn tokenize<T: Token + std::fmt::Debug>(input: &str, source: usize) -> (Vec<Spanned<T>>, Vec<Span>) {
+    let mut string = input;
+    let mut tokens = vec![];
+    let mut unrecognized: Vec<Span> = vec![];
+    while let Some(character) = string.chars().next() {
+        let start = input.len() - string.len();
+        match T::next(string) {
+            Some((token, next_string)) => {
+                string = next_string;
+                if !token.is_whitespace() {
+                    tokens.push(Spanned { token, span: Span { source, start, end: input.len() - string.len() } });
+                }
+            },
+            None => {
+                string = &string[character.len_utf8()..];
+                let end = input.len() - string.len();
+                match unrecognized.last_mut() {
+                    Some(span) if span.end == start => span.end = end,
+                    _ => unrecognized.push(Span { source, start, end }),
+                }
+            },
         }
     }
-    return_value
+    (tokens, unrecognized)
 }
 
 fn parse_pattern(pattern: &str, text: &str) -> Option<(String, usize)> {
@@ -1,7 +1,7 @@
-use strum_macros::EnumString;
+use strum_macros::{EnumString, EnumVariantNames};
 use serde_derive::{Serialize, Deserialize};
 
-#[derive(Debug, EnumString, Clone, Copy)]
+#[derive(Debug, EnumString, EnumVariantNames, Clone, Copy)]
 pub enum Instructions {
     NoOperation,
     Halt,
@@ -113,12 +113,119 @@ pub enum Instructions {
     PopMemoryShort,
     PopMemoryInt,
     PopMemoryLong,
+    InterruptReturn,
+    HaltRegister,
+    HaltImmediate,
 
     // nothing after this
     __END__
 }
 
-#[derive(Debug, EnumString, Clone, Copy)]
+pub enum RegisterRoles {
+    StackPointer = 14,
+    ProgramCounter = 15,
+}
+
+// the assembler names registers r0 to r15, with sp and pc for the two that have a role.
+pub fn register_index(name: &str) -> Option<u8> {
+    match name {
+        "sp" => Some(RegisterRoles::StackPointer as u8),
+        "pc" => Some(RegisterRoles::ProgramCounter as u8),
+        _ => name.strip_prefix('r')
+            .filter(|number| !number.starts_with('0') || *number == "0")
+            .and_then(|number| number.parse().ok())
+            .filter(|index| *index < 16),
+    }
+}
+
+// what the bytes after an instruction's opcode mean, in the order the vm reads them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    // two registers packed into one byte, one per nibble.
+    RegisterPair,
+    Register,
+    Immediate8,
+    Immediate16,
+    Immediate32,
+    Immediate64,
+    Address,
+    SysCall,
+}
+
+impl Operand {
+    pub fn size(self) -> usize {
+        match self {
+            Operand::RegisterPair | Operand::Register | Operand::Immediate8 | Operand::SysCall => 1,
+            Operand::Immediate16 => 2,
+            Operand::Immediate32 => 4,
+            Operand::Immediate64 | Operand::Address => 8,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Operand::RegisterPair => "register pair",
+            Operand::Register => "register",
+            Operand::Immediate8 => "8 bit immediate",
+            Operand::Immediate16 => "16 bit immediate",
+            Operand::Immediate32 => "32 bit immediate",
+            Operand::Immediate64 => "64 bit immediate",
+            Operand::Address => "address",
+            Operand::SysCall => "syscall",
+        }
+    }
+}
+
+impl Instructions {
+    // the instruction an opcode stands for, None for opcodes past the last one.
+    pub fn from_opcode(opcode: u16) -> Option<Self> {
+        use std::str::FromStr;
+        use strum::VariantNames;
+        Self::VARIANTS.get(opcode as usize).and_then(|name| Self::from_str(name).ok())
+            .filter(|instruction| !matches!(instruction, Instructions::__END__))
+    }
+
+    pub fn operands(self) -> &'static [Operand] {
+        use Instructions::*;
+        use Operand::*;
+        match self {
+            NoOperation | Halt | InterruptReturn => &[],
+            Instructions::SysCall => &[Operand::SysCall],
+            HaltImmediate => &[Immediate8],
+            HaltRegister | IncrementRegister | DecrementRegister
+            | BitwiseNotRegisterByte | BitwiseNotRegisterShort | BitwiseNotRegisterInt | BitwiseNotRegisterLong
+            | PushRegisterByte | PushRegisterShort | PushRegisterInt | PushRegisterLong
+            | PopRegisterByte | PopRegisterShort | PopRegisterInt | PopRegisterLong => &[Register],
+            AddRegistersByte | AddRegistersShort | AddRegistersInt | AddRegistersLong
+            | SubtractRegistersByte | SubtractRegistersShort | SubtractRegistersInt | SubtractRegistersLong
+            | BitwiseAndRegistersByte | BitwiseAndRegistersShort | BitwiseAndRegistersInt | BitwiseAndRegistersLong
+            | BitwiseOrRegistersByte | BitwiseOrRegistersShort | BitwiseOrRegistersInt | BitwiseOrRegistersLong
+            | BitwiseXOrRegistersByte | BitwiseXOrRegistersShort | BitwiseXOrRegistersInt | BitwiseXOrRegistersLong
+            | CompareRegisterByte | CompareRegisterShort | CompareRegisterInt | CompareRegisterLong
+            | MoveRegistersByte | MoveRegistersShort | MoveRegistersInt | MoveRegistersLong
+            | MoveAddressedRegisterRegisterByte | MoveAddressedRegisterRegisterShort | MoveAddressedRegisterRegisterInt | MoveAddressedRegisterRegisterLong
+            | MoveRegisterAddressedRegisterByte | MoveRegisterAddressedRegisterShort | MoveRegisterAddressedRegisterInt | MoveRegisterAddressedRegisterLong
+            | MoveAddressedRegistersByte | MoveAddressedRegistersShort | MoveAddressedRegistersInt | MoveAddressedRegistersLong => &[RegisterPair],
+            LoadRegisterByte | AddRegisterImmediateByte | SubtractRegisterImmediateByte | CompareRegisterLiteralByte
+            | BitwiseAndRegisterImmediateByte | BitwiseOrRegisterImmediateByte | BitwiseXOrRegisterImmediateByte => &[Register, Immediate8],
+            LoadRegisterShort | AddRegisterImmediateShort | SubtractRegisterImmediateShort | CompareRegisterLiteralShort
+            | BitwiseAndRegisterImmediateShort | BitwiseOrRegisterImmediateShort | BitwiseXOrRegisterImmediateShort => &[Register, Immediate16],
+            LoadRegisterInt | AddRegisterImmediateInt | SubtractRegisterImmediateInt | CompareRegisterLiteralInt
+            | BitwiseAndRegisterImmediateInt | BitwiseOrRegisterImmediateInt | BitwiseXOrRegisterImmediateInt => &[Register, Immediate32],
+            LoadRegisterLong | AddRegisterImmediateLong | SubtractRegisterImmediateLong | CompareRegisterLiteralLong
+            | BitwiseAndRegisterImmediateLong | BitwiseOrRegisterImmediateLong | BitwiseXOrRegisterImmediateLong => &[Register, Immediate64],
+            JumpIfGreaterThan | JumpIfZero | JumpIfLessThan | JumpIfEqualTo
+            | JumpIfNotGreaterThan | JumpIfNotZero | JumpIfNotLessThan | JumpIfNotEqualTo | JumpTo
+            | PushMemoryByte | PushMemoryShort | PushMemoryInt | PushMemoryLong
+            | PopMemoryByte | PopMemoryShort | PopMemoryInt | PopMemoryLong => &[Address],
+            MoveMemoryRegisterByte | MoveMemoryRegisterShort | MoveMemoryRegisterInt | MoveMemoryRegisterLong => &[Address, Register],
+            MoveRegisterMemoryByte | MoveRegisterMemoryShort | MoveRegisterMemoryInt | MoveRegisterMemoryLong => &[Register, Address],
+            __END__ => &[],
+        }
+    }
+}
+
+#[derive(Debug, EnumString, EnumVariantNames, Clone, Copy)]
 pub enum SysCalls {
     PrintRegister,
     PrintRegisterSigned,
@@ -137,15 +244,127 @@ pub enum SysCalls {
     DeleteDLL,
     LocateSymbol,
     CallCFunction,
+    ThreadLocalBase,
+    Sleep,
+    ClockMonotonic,
+    ClockRealtime,
+    CpuInstructionCount,
+    InterruptRegister,
+    FRead,
+    FWrite,
+    FFlush,
+    FEof,
+    FError,
+    ReadLine,
+    ReadChar,
+    ReadInteger,
+    PrintFormat,
+    PrintRegisterHex,
+    PrintRegisterBinary,
+    DirOpen,
+    DirRead,
+    DirClose,
+    FileStat,
+    FileRemove,
+    FileRename,
+    MakeDirectory,
+    ArgCount,
+    ArgGet,
+    EnvGet,
+    ProcessSpawn,
+    ProcessWait,
+    SocketTcpConnect,
+    SocketTcpListen,
+    SocketTcpAccept,
+    SocketUdpBind,
+    SocketSendTo,
+    SocketRecvFrom,
+    SocketSend,
+    SocketRecv,
+    SocketSetTimeout,
+    SocketClose,
 
     // nothing after this
     __END__
 }
 
+// values reported to guests by syscalls that can fail, failing calls themselves return -1.
+#[derive(Debug, EnumString, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCodes {
+    Success,
+    EndOfFile,
+    NotFound,
+    PermissionDenied,
+    AlreadyExists,
+    InvalidArgument,
+    InvalidHandle,
+    Interrupted,
+    TimedOut,
+    ConnectionRefused,
+    ConnectionClosed,
+    Other,
+    InvalidAddress,
+
+    // nothing after this
+    __END__
+}
+
+impl From<std::io::ErrorKind> for ErrorCodes {
+    fn from(kind: std::io::ErrorKind) -> Self {
+        use std::io::ErrorKind;
+        match kind {
+            ErrorKind::NotFound => ErrorCodes::NotFound,
+            ErrorKind::PermissionDenied => ErrorCodes::PermissionDenied,
+            ErrorKind::AlreadyExists => ErrorCodes::AlreadyExists,
+            ErrorKind::InvalidInput | ErrorKind::InvalidData => ErrorCodes::InvalidArgument,
+            ErrorKind::Interrupted => ErrorCodes::Interrupted,
+            ErrorKind::UnexpectedEof => ErrorCodes::EndOfFile,
+            // socket reads that hit their timeout report WouldBlock on some platforms.
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => ErrorCodes::TimedOut,
+            ErrorKind::ConnectionRefused => ErrorCodes::ConnectionRefused,
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe | ErrorKind::NotConnected => ErrorCodes::ConnectionClosed,
+            _ => ErrorCodes::Other,
+        }
+    }
+}
+
+// the kind field written by FileStat and returned by DirRead.
+#[derive(Debug, EnumString, Clone, Copy, PartialEq, Eq)]
+pub enum FileKinds {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+impl From<std::fs::FileType> for FileKinds {
+    fn from(file_type: std::fs::FileType) -> Self {
+        if file_type.is_file() {
+            FileKinds::File
+        } else if file_type.is_dir() {
+            FileKinds::Directory
+        } else if file_type.is_symlink() {
+            FileKinds::Symlink
+        } else {
+            FileKinds::Other
+        }
+    }
+}
+
+// bits of r2 for ProcessSpawn choosing which standard streams of the child are piped back to the guest.
+pub enum SpawnFlags {
+    PipeStdin = 1,
+    PipeStdout = 2,
+    PipeStderr = 4,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ArsenalObject {
     ArsenalLibraryObject {},
     ArsenalCompiledObject {
         data: Vec<u8>,
+        tls: Vec<u8>,
+        // syscalls the embedding application provides, by name and the id the program calls them with.
+        host_syscalls: Vec<(String, u8)>,
     },
 }
@@ -3,11 +3,25 @@ use bincode::{deserialize, serialize};
 
 pub fn extract_instructions(obj: &mut ArsenalObject) -> &mut Vec<u8> {
     match obj {
-        ArsenalObject::ArsenalCompiledObject { ref mut data } => data,
+        ArsenalObject::ArsenalCompiledObject { ref mut data, .. } => data,
         _ => panic!("instructions can only be extracted from compiled objects, not libraries"),
     }
 }
 
+pub fn extract_thread_local(obj: &mut ArsenalObject) -> &mut Vec<u8> {
+    match obj {
+        ArsenalObject::ArsenalCompiledObject { ref mut tls, .. } => tls,
+        _ => panic!("thread local templates can only be extracted from compiled objects, not libraries"),
+    }
+}
+
+pub fn extract_host_syscalls(obj: &mut ArsenalObject) -> &mut Vec<(String, u8)> {
+    match obj {
+        ArsenalObject::ArsenalCompiledObject { ref mut host_syscalls, .. } => host_syscalls,
+        _ => panic!("host syscalls can only be extracted from compiled objects, not libraries"),
+    }
+}
+
 pub fn decode(data: Vec<u8>) -> ArsenalObject {
     deserialize::<ArsenalObject>(&data[..]).expect("invalid encoded object")
 }
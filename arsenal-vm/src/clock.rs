@@ -0,0 +1,66 @@
+use std::sync::{Arc, atomic::{AtomicU64, Ordering}};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const SLEEP_SLICE_MILLIS: u64 = 5;
+
+pub enum Clock {
+    Host(Instant),
+    Virtual(VirtualClock),
+}
+
+// a clock that only moves when told to, so guest programs see the same time on every run.
+// sleeping advances it instead of blocking the thread.
+#[derive(Debug, Clone, Default)]
+pub struct VirtualClock {
+    nanos: Arc<AtomicU64>,
+}
+
+impl VirtualClock {
+    pub fn now(&self) -> u64 {
+        self.nanos.load(Ordering::SeqCst)
+    }
+
+    pub fn set(&self, nanos: u64) {
+        self.nanos.store(nanos, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, nanos: u64) {
+        self.nanos.fetch_add(nanos, Ordering::SeqCst);
+    }
+}
+
+impl Clock {
+    pub fn host() -> Self {
+        Clock::Host(Instant::now())
+    }
+
+    pub fn monotonic(&self) -> u64 {
+        match self {
+            Clock::Host(start) => start.elapsed().as_nanos() as u64,
+            Clock::Virtual(clock) => clock.now(),
+        }
+    }
+
+    pub fn realtime(&self) -> u64 {
+        match self {
+            Clock::Host(_) => SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_nanos() as u64).unwrap_or(0),
+            Clock::Virtual(clock) => clock.now(),
+        }
+    }
+
+    // sleeps for at most millis and returns how long it slept. the host clock sleeps in short slices
+    // so the caller can stop a long sleep part of the way through.
+    pub fn sleep(&self, millis: u64) -> u64 {
+        match self {
+            Clock::Host(_) => {
+                let slice = millis.min(SLEEP_SLICE_MILLIS);
+                std::thread::sleep(Duration::from_millis(slice));
+                slice
+            },
+            Clock::Virtual(clock) => {
+                clock.advance(millis.saturating_mul(1_000_000));
+                millis
+            },
+        }
+    }
+}
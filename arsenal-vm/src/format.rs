@@ -0,0 +1,135 @@
+use std::io::{BufWriter, Stdout, Write};
+use std::sync::{LazyLock, Mutex, MutexGuard};
+
+// stdout is shared by the whole process, so guest output is buffered once for every machine and thread.
+static GUEST_STDOUT: LazyLock<Mutex<BufWriter<Stdout>>> = LazyLock::new(|| Mutex::new(BufWriter::new(std::io::stdout())));
+
+pub fn guest_stdout() -> MutexGuard<'static, BufWriter<Stdout>> {
+    GUEST_STDOUT.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// the guest picks the width, so it is capped before the host allocates any padding for it.
+const MAX_WIDTH: usize = 4096;
+
+pub fn flush_guest_stdout() -> std::io::Result<()> {
+    guest_stdout().flush()
+}
+
+struct Spec {
+    left_align: bool,
+    zero_pad: bool,
+    width: usize,
+}
+
+impl Spec {
+    fn pad(&self, text: &[u8], output: &mut Vec<u8>) {
+        let padding = self.width.saturating_sub(text.len());
+        if self.left_align {
+            output.extend_from_slice(text);
+            output.extend(std::iter::repeat_n(b' ', padding));
+        } else if self.zero_pad {
+            // the sign stays in front of the zeros.
+            let (sign, digits) = match text.first() {
+                Some(b'-') => text.split_at(1),
+                _ => text.split_at(0),
+            };
+            output.extend_from_slice(sign);
+            output.extend(std::iter::repeat_n(b'0', padding));
+            output.extend_from_slice(digits);
+        } else {
+            output.extend(std::iter::repeat_n(b' ', padding));
+            output.extend_from_slice(text);
+        }
+    }
+}
+
+// expands %d %u %x %s %c and %% with optional - and 0 flags and a width of at most MAX_WIDTH.
+// next_arg hands out the argument slots in order, string_at reads the c string at a guest address for %s.
+pub fn format(format: &[u8], mut next_arg: impl FnMut() -> u64, string_at: impl Fn(u64) -> Vec<u8>) -> Vec<u8> {
+    let mut output = vec![];
+    let mut bytes = format.iter().copied().peekable();
+
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            output.push(byte);
+            continue;
+        }
+
+        let mut spec = Spec { left_align: false, zero_pad: false, width: 0 };
+        while let Some(flag @ (b'-' | b'0')) = bytes.peek().copied() {
+            match flag {
+                b'-' => spec.left_align = true,
+                _ => spec.zero_pad = true,
+            }
+            bytes.next();
+        }
+        while let Some(digit @ b'0'..=b'9') = bytes.peek().copied() {
+            spec.width = spec.width.saturating_mul(10).saturating_add((digit - b'0') as usize).min(MAX_WIDTH);
+            bytes.next();
+        }
+
+        match bytes.next() {
+            Some(b'd') => spec.pad((next_arg() as i64).to_string().as_bytes(), &mut output),
+            Some(b'u') => spec.pad(next_arg().to_string().as_bytes(), &mut output),
+            Some(b'x') => spec.pad(format!("{:x}", next_arg()).as_bytes(), &mut output),
+            Some(b'c') => spec.pad(&[next_arg() as u8], &mut output),
+            Some(b's') => {
+                let text = string_at(next_arg());
+                spec.pad(&text, &mut output);
+            },
+            Some(b'%') => output.push(b'%'),
+            // unknown conversions are printed as written.
+            Some(other) => output.extend_from_slice(&[b'%', other]),
+            None => output.push(b'%'),
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // arguments are handed out in order, %s looks its address up in strings.
+    fn expand(text: &str, args: &[u64], strings: &[(u64, &str)]) -> String {
+        let mut args = args.iter().copied();
+        let output = format(text.as_bytes(), || args.next().expect("format asked for too many arguments"), |address| {
+            strings.iter().find(|(at, _)| *at == address).map(|(_, text)| text.as_bytes().to_vec()).unwrap_or_default()
+        });
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(expand("%d %d", &[42, -7i64 as u64], &[]), "42 -7");
+        assert_eq!(expand("%u", &[u64::MAX], &[]), "18446744073709551615");
+        assert_eq!(expand("%x", &[255], &[]), "ff");
+        assert_eq!(expand("%s!", &[16], &[(16, "hello")]), "hello!");
+        assert_eq!(expand("%c%c", &[b'o' as u64, b'k' as u64], &[]), "ok");
+        assert_eq!(expand("100%%", &[], &[]), "100%");
+        assert_eq!(expand("%q", &[], &[]), "%q");
+    }
+
+    #[test]
+    fn width_and_flags() {
+        assert_eq!(expand("[%5d]", &[42], &[]), "[   42]");
+        assert_eq!(expand("[%-5d]", &[42], &[]), "[42   ]");
+        assert_eq!(expand("[%05d]", &[-42i64 as u64], &[]), "[-0042]");
+        assert_eq!(expand("[%04x]", &[10], &[]), "[000a]");
+        assert_eq!(expand("[%-4s]", &[0], &[(0, "ab")]), "[ab  ]");
+        assert_eq!(expand("[%2d]", &[12345], &[]), "[12345]");
+    }
+
+    #[test]
+    fn width_is_capped() {
+        assert_eq!(expand("%4000000000d", &[1], &[]).len(), MAX_WIDTH);
+        assert_eq!(expand("%99999999999999999999999d", &[1], &[]).len(), MAX_WIDTH);
+    }
+
+    #[test]
+    fn truncated_spec() {
+        assert_eq!(expand("50%", &[], &[]), "50%");
+        assert_eq!(expand("50%-08", &[], &[]), "50%");
+    }
+}
@@ -0,0 +1,415 @@
+use std::ffi::CString;
+use std::fs::ReadDir;
+use std::io::{BufRead, Read, Write};
+use std::iter::Peekable;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::path::Path;
+use std::process::Child;
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+
+use arsenal_globals::{ErrorCodes, FileKinds};
+
+use crate::format::{guest_stdout, flush_guest_stdout};
+
+pub type HandleResult<T> = Result<T, ErrorCodes>;
+
+// a libc stream owned by the machine, closed when dropped if the guest never closed it.
+pub struct CFile(*mut libc::FILE);
+
+// the stream is only ever used behind the handle's mutex.
+unsafe impl Send for CFile {}
+
+impl Drop for CFile {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe {
+                libc::fclose(self.0);
+            }
+        }
+    }
+}
+
+// a child process owned by the machine, killed and reaped when dropped so it never outlives the handle as a zombie.
+pub struct ChildProcess(pub Child);
+
+impl Drop for ChildProcess {
+    fn drop(&mut self) {
+        // both fail harmlessly once the process has already been waited for.
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+pub enum HostHandle {
+    File(CFile),
+    Stdin { eof: bool },
+    Stdout,
+    Stderr,
+    Directory(Peekable<ReadDir>),
+    Process(ChildProcess),
+    Reader { reader: Box<dyn Read + Send>, eof: bool },
+    Writer(Box<dyn Write + Send>),
+    TcpStream(TcpStream),
+    // the listener is nonblocking, accepting polls it until the timeout set on the handle runs out.
+    TcpListener { listener: TcpListener, timeout: Option<Duration> },
+    UdpSocket(UdpSocket),
+    Closed,
+}
+
+pub const STDIN_HANDLE: u64 = 0;
+pub const STDOUT_HANDLE: u64 = 1;
+pub const STDERR_HANDLE: u64 = 2;
+
+fn last_error() -> ErrorCodes {
+    std::io::Error::last_os_error().kind().into()
+}
+
+pub fn io_error(error: std::io::Error) -> ErrorCodes {
+    error.kind().into()
+}
+
+// makes sure a prompt printed without a newline is visible before blocking on input.
+pub fn read_stdin(buffer: &mut [u8]) -> HandleResult<usize> {
+    flush_guest_stdout().map_err(io_error)?;
+    std::io::stdin().lock().read(buffer).map_err(io_error)
+}
+
+// the line without its terminator, None at the end of input.
+pub fn read_stdin_line() -> HandleResult<Option<String>> {
+    flush_guest_stdout().map_err(io_error)?;
+    let mut line = String::new();
+    if std::io::stdin().lock().read_line(&mut line).map_err(io_error)? == 0 {
+        return Ok(None);
+    }
+    let length = line.trim_end_matches(['\n', '\r']).len();
+    line.truncate(length);
+    Ok(Some(line))
+}
+
+impl HostHandle {
+    pub fn open_file(path: &Path, mode: &str) -> HandleResult<Self> {
+        let path = path.to_str().and_then(|path| CString::new(path).ok()).ok_or(ErrorCodes::InvalidArgument)?;
+        let mode = CString::new(mode).map_err(|_| ErrorCodes::InvalidArgument)?;
+        let file = unsafe { libc::fopen(path.as_ptr(), mode.as_ptr()) };
+        if file.is_null() {
+            return Err(last_error());
+        }
+        Ok(HostHandle::File(CFile(file)))
+    }
+
+    fn file(&mut self) -> HandleResult<*mut libc::FILE> {
+        match self {
+            HostHandle::File(file) => Ok(file.0),
+            _ => Err(ErrorCodes::InvalidHandle),
+        }
+    }
+
+    pub fn close(&mut self) -> HandleResult<()> {
+        match std::mem::replace(self, HostHandle::Closed) {
+            HostHandle::File(mut file) => {
+                let result = unsafe { libc::fclose(file.0) };
+                file.0 = std::ptr::null_mut();
+                if result != 0 { Err(last_error()) } else { Ok(()) }
+            },
+            HostHandle::Closed => Err(ErrorCodes::InvalidHandle),
+            // everything else is released by being dropped here.
+            _ => Ok(()),
+        }
+    }
+
+    pub fn open_directory(path: &Path) -> HandleResult<Self> {
+        Ok(HostHandle::Directory(std::fs::read_dir(path).map_err(io_error)?.peekable()))
+    }
+
+    // the next entry's name and kind, None once every entry has been read.
+    // fits decides whether the name can be handed to the guest, an entry that does not fit stays for the next read.
+    pub fn read_directory(&mut self, fits: impl Fn(&str) -> bool) -> HandleResult<Option<(String, FileKinds)>> {
+        let HostHandle::Directory(entries) = self else {
+            return Err(ErrorCodes::InvalidHandle);
+        };
+        let name = match entries.peek() {
+            None => return Ok(None),
+            Some(Err(_)) => return Err(io_error(entries.next().unwrap().unwrap_err())),
+            Some(Ok(entry)) => entry.file_name().to_string_lossy().into_owned(),
+        };
+        if !fits(&name) {
+            return Err(ErrorCodes::InvalidArgument);
+        }
+        let entry = entries.next().unwrap().map_err(io_error)?;
+        let kind = entry.file_type().map(FileKinds::from).unwrap_or(FileKinds::Other);
+        Ok(Some((name, kind)))
+    }
+
+    // the exit code of the process once it has finished, None while it is still running.
+    // a process killed by a signal has no exit code and reports -1.
+    pub fn try_wait(&mut self) -> HandleResult<Option<u64>> {
+        let HostHandle::Process(child) = self else {
+            return Err(ErrorCodes::InvalidHandle);
+        };
+        let status = child.0.try_wait().map_err(io_error)?;
+        Ok(status.map(|status| status.code().map_or(u64::MAX, |code| code as i64 as u64)))
+    }
+
+    fn resolve(address: &str) -> HandleResult<Vec<SocketAddr>> {
+        Ok(address.to_socket_addrs().map_err(io_error)?.collect())
+    }
+
+    // a timeout of None waits for as long as the operating system does.
+    pub fn tcp_connect(address: &str, timeout: Option<Duration>) -> HandleResult<Self> {
+        let mut last = ErrorCodes::NotFound;
+        for address in Self::resolve(address)? {
+            let stream = match timeout {
+                Some(timeout) => TcpStream::connect_timeout(&address, timeout),
+                None => TcpStream::connect(address),
+            };
+            match stream {
+                Ok(stream) => return Ok(HostHandle::TcpStream(stream)),
+                Err(error) => last = io_error(error),
+            }
+        }
+        Err(last)
+    }
+
+    pub fn tcp_listen(address: &str) -> HandleResult<Self> {
+        let listener = TcpListener::bind(&Self::resolve(address)?[..]).map_err(io_error)?;
+        listener.set_nonblocking(true).map_err(io_error)?;
+        Ok(HostHandle::TcpListener { listener, timeout: None })
+    }
+
+    pub fn udp_bind(address: &str) -> HandleResult<Self> {
+        Ok(HostHandle::UdpSocket(UdpSocket::bind(&Self::resolve(address)?[..]).map_err(io_error)?))
+    }
+
+    // the next connection, None while nobody is waiting to connect.
+    pub fn try_accept(&mut self) -> HandleResult<Option<Self>> {
+        let HostHandle::TcpListener { listener, .. } = self else {
+            return Err(ErrorCodes::InvalidHandle);
+        };
+        match listener.accept() {
+            Ok((stream, _)) => {
+                // some platforms hand out connections as nonblocking as the listener.
+                stream.set_nonblocking(false).map_err(io_error)?;
+                Ok(Some(HostHandle::TcpStream(stream)))
+            },
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => Ok(None),
+            Err(error) => Err(io_error(error)),
+        }
+    }
+
+    // how long a call polling this handle waits before giving up, None waits forever.
+    pub fn timeout(&self) -> Option<Duration> {
+        match self {
+            HostHandle::TcpListener { timeout, .. } => *timeout,
+            _ => None,
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> HandleResult<()> {
+        match self {
+            HostHandle::TcpStream(stream) => stream.set_read_timeout(timeout).and_then(|_| stream.set_write_timeout(timeout)),
+            HostHandle::UdpSocket(socket) => socket.set_read_timeout(timeout).and_then(|_| socket.set_write_timeout(timeout)),
+            HostHandle::TcpListener { timeout: current, .. } => {
+                *current = timeout;
+                Ok(())
+            },
+            _ => return Err(ErrorCodes::InvalidHandle),
+        }.map_err(io_error)
+    }
+
+    pub fn send(&mut self, buffer: &[u8]) -> HandleResult<usize> {
+        match self {
+            HostHandle::TcpStream(stream) => stream.write(buffer),
+            HostHandle::UdpSocket(socket) => socket.send(buffer),
+            _ => return Err(ErrorCodes::InvalidHandle),
+        }.map_err(io_error)
+    }
+
+    pub fn recv(&mut self, buffer: &mut [u8]) -> HandleResult<usize> {
+        match self {
+            HostHandle::TcpStream(stream) => stream.read(buffer),
+            HostHandle::UdpSocket(socket) => socket.recv(buffer),
+            _ => return Err(ErrorCodes::InvalidHandle),
+        }.map_err(io_error)
+    }
+
+    pub fn send_to(&mut self, buffer: &[u8], address: &str) -> HandleResult<usize> {
+        let HostHandle::UdpSocket(socket) = self else {
+            return Err(ErrorCodes::InvalidHandle);
+        };
+        socket.send_to(buffer, &Self::resolve(address)?[..]).map_err(io_error)
+    }
+
+    pub fn recv_from(&mut self, buffer: &mut [u8]) -> HandleResult<(usize, SocketAddr)> {
+        let HostHandle::UdpSocket(socket) = self else {
+            return Err(ErrorCodes::InvalidHandle);
+        };
+        socket.recv_from(buffer).map_err(io_error)
+    }
+
+    // None once the end of the stream is reached.
+    pub fn getc(&mut self) -> HandleResult<Option<u8>> {
+        if let HostHandle::Stdin { .. } | HostHandle::Reader { .. } | HostHandle::TcpStream(_) = self {
+            let mut byte = [0];
+            return Ok((self.read(&mut byte)? == 1).then_some(byte[0]));
+        }
+        let file = self.file()?;
+        unsafe {
+            match libc::fgetc(file) {
+                libc::EOF if libc::ferror(file) != 0 => Err(last_error()),
+                libc::EOF => Ok(None),
+                byte => Ok(Some(byte as u8)),
+            }
+        }
+    }
+
+    pub fn putc(&mut self, byte: u8) -> HandleResult<u8> {
+        if let HostHandle::Stdout | HostHandle::Stderr | HostHandle::Writer(_) | HostHandle::TcpStream(_) = self {
+            return self.write(&[byte]).map(|_| byte);
+        }
+        let file = self.file()?;
+        unsafe {
+            match libc::fputc(byte as i32, file) {
+                libc::EOF => Err(last_error()),
+                _ => Ok(byte),
+            }
+        }
+    }
+
+    pub fn read(&mut self, buffer: &mut [u8]) -> HandleResult<usize> {
+        if let HostHandle::Stdin { eof } = self {
+            let read = read_stdin(buffer)?;
+            *eof = read == 0 && !buffer.is_empty();
+            return Ok(read);
+        }
+        if let HostHandle::Reader { reader, eof } = self {
+            let read = reader.read(buffer).map_err(io_error)?;
+            *eof = read == 0 && !buffer.is_empty();
+            return Ok(read);
+        }
+        if let HostHandle::TcpStream(stream) = self {
+            return stream.read(buffer).map_err(io_error);
+        }
+        let file = self.file()?;
+        unsafe {
+            let read = libc::fread(buffer.as_mut_ptr() as *mut libc::c_void, 1, buffer.len(), file);
+            if read < buffer.len() && libc::ferror(file) != 0 {
+                return Err(last_error());
+            }
+            Ok(read)
+        }
+    }
+
+    pub fn write(&mut self, buffer: &[u8]) -> HandleResult<usize> {
+        match self {
+            HostHandle::Stdout => return guest_stdout().write_all(buffer).map(|_| buffer.len()).map_err(io_error),
+            HostHandle::Stderr => return std::io::stderr().write_all(buffer).map(|_| buffer.len()).map_err(io_error),
+            HostHandle::Writer(writer) => return writer.write_all(buffer).map(|_| buffer.len()).map_err(io_error),
+            HostHandle::TcpStream(stream) => return stream.write_all(buffer).map(|_| buffer.len()).map_err(io_error),
+            _ => {},
+        }
+        let file = self.file()?;
+        unsafe {
+            let written = libc::fwrite(buffer.as_ptr() as *const libc::c_void, 1, buffer.len(), file);
+            if written < buffer.len() {
+                return Err(last_error());
+            }
+            Ok(written)
+        }
+    }
+
+    pub fn flush(&mut self) -> HandleResult<()> {
+        match self {
+            HostHandle::Stdout => return flush_guest_stdout().map_err(io_error),
+            HostHandle::Stderr => return std::io::stderr().flush().map_err(io_error),
+            HostHandle::Writer(writer) => return writer.flush().map_err(io_error),
+            HostHandle::TcpStream(stream) => return stream.flush().map_err(io_error),
+            _ => {},
+        }
+        let file = self.file()?;
+        match unsafe { libc::fflush(file) } {
+            0 => Ok(()),
+            _ => Err(last_error()),
+        }
+    }
+
+    pub fn eof(&mut self) -> HandleResult<bool> {
+        match self {
+            HostHandle::Stdin { eof } | HostHandle::Reader { eof, .. } => return Ok(*eof),
+            HostHandle::Stdout | HostHandle::Stderr | HostHandle::Writer(_) | HostHandle::TcpStream(_) => return Ok(false),
+            _ => {},
+        }
+        let file = self.file()?;
+        Ok(unsafe { libc::feof(file) } != 0)
+    }
+
+    pub fn error(&mut self) -> HandleResult<bool> {
+        if let HostHandle::Stdin { .. } | HostHandle::Stdout | HostHandle::Stderr | HostHandle::Reader { .. } | HostHandle::Writer(_) | HostHandle::TcpStream(_) = self {
+            return Ok(false);
+        }
+        let file = self.file()?;
+        Ok(unsafe { libc::ferror(file) } != 0)
+    }
+
+    pub fn tell(&mut self) -> HandleResult<u64> {
+        let file = self.file()?;
+        match unsafe { libc::ftell(file) } {
+            position if position < 0 => Err(last_error()),
+            position => Ok(position as u64),
+        }
+    }
+
+    pub fn seek(&mut self, offset: i64, whence: i32) -> HandleResult<()> {
+        let file = self.file()?;
+        match unsafe { libc::fseek(file, offset as libc::c_long, whence) } {
+            0 => Ok(()),
+            _ => Err(last_error()),
+        }
+    }
+}
+
+// a host object and the error of the last operation on it that failed, which is what FError reports.
+pub struct HandleEntry {
+    pub handle: HostHandle,
+    pub error: ErrorCodes,
+}
+
+// maps the small integers guests see to host objects, every id is checked before it is used.
+// entries are shared so a slow operation on one handle does not hold up the whole table.
+#[derive(Default)]
+pub struct HandleTable {
+    entries: Vec<Option<Arc<Mutex<HandleEntry>>>>,
+}
+
+impl HandleTable {
+    // starts with stdin, stdout and stderr already open as handles 0, 1 and 2.
+    pub fn new() -> Self {
+        let mut table = Self::default();
+        table.insert(HostHandle::Stdin { eof: false });
+        table.insert(HostHandle::Stdout);
+        table.insert(HostHandle::Stderr);
+        table
+    }
+
+    pub fn insert(&mut self, handle: HostHandle) -> u64 {
+        let entry = Some(Arc::new(Mutex::new(HandleEntry { handle, error: ErrorCodes::Success })));
+        match self.entries.iter().position(Option::is_none) {
+            Some(free) => {
+                self.entries[free] = entry;
+                free as u64
+            },
+            None => {
+                self.entries.push(entry);
+                (self.entries.len() - 1) as u64
+            },
+        }
+    }
+
+    pub fn get(&self, id: u64) -> HandleResult<Arc<Mutex<HandleEntry>>> {
+        self.entries.get(id as usize).and_then(Option::clone).ok_or(ErrorCodes::InvalidHandle)
+    }
+
+    pub fn remove(&mut self, id: u64) -> HandleResult<Arc<Mutex<HandleEntry>>> {
+        self.entries.get_mut(id as usize).and_then(Option::take).ok_or(ErrorCodes::InvalidHandle)
+    }
+}
@@ -0,0 +1,52 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const INTERRUPT_LINES: usize = 64;
+pub const NO_HANDLER: u64 = u64::MAX;
+
+pub struct InterruptController {
+    pending: AtomicU64,
+    handlers: [AtomicU64; INTERRUPT_LINES],
+}
+
+pub struct InterruptFrame {
+    pub registers: [u64; 16],
+    pub alu_flags: u8,
+}
+
+impl Default for InterruptController {
+    fn default() -> Self {
+        Self {
+            pending: AtomicU64::new(0),
+            handlers: std::array::from_fn(|_| AtomicU64::new(NO_HANDLER)),
+        }
+    }
+}
+
+impl InterruptController {
+    pub fn raise(&self, line: u8) {
+        assert!((line as usize) < INTERRUPT_LINES, "interrupt line {line} does not exist.");
+        self.pending.fetch_or(1 << line, Ordering::SeqCst);
+    }
+
+    pub fn register(&self, line: u8, handler: u64) {
+        assert!((line as usize) < INTERRUPT_LINES, "interrupt line {line} does not exist.");
+        self.handlers[line as usize].store(handler, Ordering::SeqCst);
+    }
+
+    // claims the lowest pending line that has a handler, lines without one stay pending until a handler is registered.
+    pub fn take(&self) -> Option<(u8, u64)> {
+        let mut pending = self.pending.load(Ordering::SeqCst);
+        while pending != 0 {
+            let line = pending.trailing_zeros() as u8;
+            pending &= !(1 << line);
+            let handler = self.handlers[line as usize].load(Ordering::SeqCst);
+            if handler == NO_HANDLER {
+                continue;
+            }
+            if self.pending.fetch_and(!(1 << line), Ordering::SeqCst) & (1 << line) != 0 {
+                return Some((line, handler));
+            }
+        }
+        None
+    }
+}
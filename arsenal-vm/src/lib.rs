@@ -1,4 +1,9 @@
 extern crate arsenal_globals;
 
 pub mod virtual_machine;
-pub mod virtual_thread;
\ No newline at end of file
+pub mod virtual_thread;
+pub mod clock;
+pub mod interrupts;
+pub mod handles;
+pub mod format;
+pub mod permissions;
@@ -0,0 +1,257 @@
+use std::path::{Component, Path, PathBuf};
+
+use arsenal_globals::SysCalls::{self, *};
+
+// syscalls that reach outside the machine, anything not listed here is always allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SysCallGroup {
+    FileSystem,
+    Network,
+    Process,
+    Environment,
+    Ffi,
+}
+
+const GROUPS: &[(SysCalls, SysCallGroup)] = &[
+    (FOpen, SysCallGroup::FileSystem),
+    (DirOpen, SysCallGroup::FileSystem),
+    (FileStat, SysCallGroup::FileSystem),
+    (FileRemove, SysCallGroup::FileSystem),
+    (FileRename, SysCallGroup::FileSystem),
+    (MakeDirectory, SysCallGroup::FileSystem),
+    (SocketTcpConnect, SysCallGroup::Network),
+    (SocketTcpListen, SysCallGroup::Network),
+    (SocketUdpBind, SysCallGroup::Network),
+    (ProcessSpawn, SysCallGroup::Process),
+    (EnvGet, SysCallGroup::Environment),
+    (LoadDLL, SysCallGroup::Ffi),
+    (DeleteDLL, SysCallGroup::Ffi),
+    (LocateSymbol, SysCallGroup::Ffi),
+    (CallCFunction, SysCallGroup::Ffi),
+];
+
+// the registers holding a path for the filesystem calls.
+const PATH_REGISTERS: &[(SysCalls, &[usize])] = &[
+    (FOpen, &[0]),
+    (DirOpen, &[0]),
+    (FileStat, &[0]),
+    (FileRemove, &[0]),
+    (FileRename, &[0, 1]),
+    (MakeDirectory, &[0]),
+];
+
+impl SysCallGroup {
+    // the names used by the --deny-<group> and --allow-<group> flags.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "fs" => Some(SysCallGroup::FileSystem),
+            "net" => Some(SysCallGroup::Network),
+            "process" => Some(SysCallGroup::Process),
+            "env" => Some(SysCallGroup::Environment),
+            "ffi" => Some(SysCallGroup::Ffi),
+            _ => None,
+        }
+    }
+
+    pub fn of(call_id: u8) -> Option<Self> {
+        GROUPS.iter().find(|(call, _)| *call as u8 == call_id).map(|(_, group)| *group)
+    }
+}
+
+pub fn path_registers(call_id: u8) -> &'static [usize] {
+    PATH_REGISTERS.iter().find(|(call, _)| *call as u8 == call_id).map_or(&[], |(_, registers)| registers)
+}
+
+// everything is allowed until a group is denied or the filesystem is limited to a set of directories.
+#[derive(Debug, Clone, Default)]
+pub struct Permissions {
+    denied: Vec<SysCallGroup>,
+    // groups that stay allowed when limiting the filesystem would deny them.
+    allowed: Vec<SysCallGroup>,
+    filesystem_roots: Option<Vec<PathBuf>>,
+}
+
+impl Permissions {
+    pub fn deny(&mut self, group: SysCallGroup) {
+        self.allowed.retain(|allowed| *allowed != group);
+        if !self.denied.contains(&group) {
+            self.denied.push(group);
+        }
+    }
+
+    pub fn allow(&mut self, group: SysCallGroup) {
+        self.denied.retain(|denied| *denied != group);
+        if !self.allowed.contains(&group) {
+            self.allowed.push(group);
+        }
+    }
+
+    // relative directories are taken from the current directory at the time of the call.
+    pub fn allow_filesystem(&mut self, root: &Path) {
+        let root = std::path::absolute(root).unwrap_or_else(|_| root.to_path_buf());
+        self.filesystem_roots.get_or_insert_with(Vec::new).push(resolve(&root));
+    }
+
+    pub fn allows_call(&self, call_id: u8) -> bool {
+        self.allows_group(SysCallGroup::of(call_id))
+    }
+
+    // a spawned program or native code opens whatever files it likes, so limiting the filesystem
+    // denies spawning and ffi too unless they were allowed again.
+    pub fn allows_group(&self, group: Option<SysCallGroup>) -> bool {
+        match group {
+            Some(group @ (SysCallGroup::Process | SysCallGroup::Ffi)) if self.filesystem_roots.is_some() => self.allowed.contains(&group),
+            Some(group) => !self.denied.contains(&group),
+            None => true,
+        }
+    }
+
+    pub fn allows_path(&self, path: &Path) -> bool {
+        match &self.filesystem_roots {
+            Some(roots) => {
+                let path = resolve(path);
+                roots.iter().any(|root| path.starts_with(root))
+            },
+            None => true,
+        }
+    }
+}
+
+// how many dangling links in a row are followed before giving up, a loop of links never resolves.
+const MAX_LINKS: usize = 40;
+
+// follows symlinks in the part of the path that exists so a link cannot lead out of an allowed directory,
+// the missing rest is normalized by hand since the file may be about to be created.
+fn resolve(path: &Path) -> PathBuf {
+    resolve_links(path, 0)
+}
+
+fn resolve_links(path: &Path, links: usize) -> PathBuf {
+    let mut existing = path;
+    let mut missing = vec![];
+    let mut resolved = loop {
+        if let Ok(canonical) = existing.canonicalize() {
+            break canonical;
+        }
+        // a dangling link does not canonicalize, but creating a file through it creates the file it points at.
+        if let Ok(target) = existing.read_link() {
+            if links == MAX_LINKS {
+                return PathBuf::new();
+            }
+            break resolve_links(&existing.parent().unwrap_or(Path::new("")).join(target), links + 1);
+        }
+        match existing.parent() {
+            Some(parent) => {
+                missing.extend(existing.components().next_back());
+                existing = parent;
+            },
+            None => break PathBuf::new(),
+        }
+    };
+    for component in missing.into_iter().rev() {
+        match component {
+            Component::ParentDir => { resolved.pop(); },
+            Component::CurDir => {},
+            other => resolved.push(other),
+        }
+    }
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a fresh directory per test with an allowed and an outside folder in it, tests run in parallel.
+    fn scratch(name: &str) -> (PathBuf, PathBuf) {
+        let directory = std::env::temp_dir().join(format!("arsenal-permissions-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(directory.join("allowed")).unwrap();
+        std::fs::create_dir_all(directory.join("outside")).unwrap();
+        std::fs::write(directory.join("outside/secret"), "").unwrap();
+        (directory.join("allowed"), directory.join("outside"))
+    }
+
+    fn limited_to(root: &Path) -> Permissions {
+        let mut permissions = Permissions::default();
+        permissions.allow_filesystem(root);
+        permissions
+    }
+
+    #[test]
+    fn denied_groups_only_block_their_own_calls() {
+        let mut permissions = Permissions::default();
+        permissions.deny(SysCallGroup::Ffi);
+        assert!(!permissions.allows_call(CallCFunction as u8));
+        assert!(!permissions.allows_call(LoadDLL as u8));
+        assert!(permissions.allows_call(FOpen as u8));
+        assert!(permissions.allows_call(ProcessSpawn as u8));
+        assert!(permissions.allows_call(PrintCString as u8));
+    }
+
+    #[test]
+    fn limiting_the_filesystem_denies_spawning_and_ffi() {
+        let (allowed, _) = scratch("spawn");
+        let permissions = limited_to(&allowed);
+        assert!(!permissions.allows_call(ProcessSpawn as u8));
+        assert!(!permissions.allows_call(LoadDLL as u8));
+        assert!(!permissions.allows_call(CallCFunction as u8));
+        assert!(permissions.allows_call(FOpen as u8));
+    }
+
+    #[test]
+    fn allowed_groups_survive_limiting_the_filesystem() {
+        let (allowed, _) = scratch("allow");
+        let mut permissions = limited_to(&allowed);
+        permissions.allow(SysCallGroup::Ffi);
+        assert!(permissions.allows_call(LoadDLL as u8));
+        assert!(!permissions.allows_call(ProcessSpawn as u8));
+        permissions.deny(SysCallGroup::Ffi);
+        assert!(!permissions.allows_call(LoadDLL as u8));
+    }
+
+    #[test]
+    fn every_path_is_allowed_without_roots() {
+        assert!(Permissions::default().allows_path(Path::new("/etc/passwd")));
+    }
+
+    #[test]
+    fn parent_components_cannot_leave_the_root() {
+        let (allowed, outside) = scratch("parent");
+        let permissions = limited_to(&allowed);
+        assert!(permissions.allows_path(&allowed.join("new_file")));
+        assert!(permissions.allows_path(&allowed.join("missing/../new_file")));
+        assert!(!permissions.allows_path(&allowed.join("../outside/secret")));
+        assert!(!permissions.allows_path(&allowed.join("missing/../../outside/secret")));
+        assert!(!permissions.allows_path(&allowed.join("missing/deeper/../../../outside/new_file")));
+        assert!(!permissions.allows_path(&outside));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_out_of_the_root_are_followed() {
+        let (allowed, outside) = scratch("symlink");
+        std::os::unix::fs::symlink(&outside, allowed.join("link")).unwrap();
+        std::os::unix::fs::symlink(allowed.join("missing"), allowed.join("dangling")).unwrap();
+        std::os::unix::fs::symlink(outside.join("new_file"), allowed.join("dangling_out")).unwrap();
+        std::os::unix::fs::symlink("loop", allowed.join("loop")).unwrap();
+        let permissions = limited_to(&allowed);
+        assert!(!permissions.allows_path(&allowed.join("link")));
+        assert!(!permissions.allows_path(&allowed.join("link/secret")));
+        assert!(!permissions.allows_path(&allowed.join("link/new_file")));
+        assert!(permissions.allows_path(&allowed.join("dangling")));
+        assert!(!permissions.allows_path(&allowed.join("dangling_out")));
+        assert!(!permissions.allows_path(&allowed.join("loop")));
+    }
+
+    #[test]
+    fn relative_paths_are_taken_from_the_current_directory() {
+        // cargo runs tests from the crate's folder.
+        let permissions = limited_to(Path::new("src"));
+        assert!(permissions.allows_path(Path::new("src/lib.rs")));
+        assert!(permissions.allows_path(Path::new("src/new_file")));
+        assert!(permissions.allows_path(Path::new("./src/../src/lib.rs")));
+        assert!(!permissions.allows_path(Path::new("Cargo.toml")));
+        assert!(!permissions.allows_path(Path::new("src/missing/../../Cargo.toml")));
+    }
+}
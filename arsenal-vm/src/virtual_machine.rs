@@ -2,16 +2,22 @@
 
 use std::slice::SliceIndex;
 use std::ptr::{read_unaligned, write_unaligned};
-use std::sync::Arc;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use std::path::PathBuf;
 
 use crate::virtual_thread::*;
+use crate::clock::{Clock, VirtualClock};
+use crate::interrupts::InterruptController;
+use crate::handles::{ChildProcess, HandleTable, HandleResult, HostHandle, read_stdin, read_stdin_line, io_error};
+use crate::format::guest_stdout;
+use crate::permissions::{Permissions, SysCallGroup, path_registers};
 
-pub enum RegisterRoles {
-    StackPointer = 14,
-    ProgramCounter = 15,
-}
-
-use arsenal_globals::{Instructions, SysCalls};
+pub use arsenal_globals::RegisterRoles;
+use arsenal_globals::{Instructions, Operand, SysCalls, ErrorCodes, FileKinds, SpawnFlags};
 
 pub enum ALUFlags {
     Zero = 0,
@@ -20,11 +26,89 @@ pub enum ALUFlags {
     Equal = 8,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    ProgramCounterOutOfBounds,
+    InvalidInstruction(u16),
+    InvalidSysCall(u8),
+    InvalidInterruptLine(u8),
+    InterruptReturnOutsideHandler,
+    InvalidRegister(u64),
+    InvalidAddress(u64),
+    StackPointerOutOfBounds(u64),
+    PermissionDenied(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    Halted(u8),
+    Fault(FaultKind, u64),
+    BudgetExhausted,
+    DeadlineExceeded,
+    Cancelled,
+}
+
+impl ExitReason {
+    // faults and stopped runs use the codes of the usual unix tools so scripts can tell them apart from a guest exit code.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ExitReason::Halted(code) => *code as i32,
+            ExitReason::Fault(_, _) => 70,
+            ExitReason::BudgetExhausted => 124,
+            // 128 + SIGALRM, what a shell reports for a process stopped by a timer.
+            ExitReason::DeadlineExceeded => 142,
+            ExitReason::Cancelled => 130,
+        }
+    }
+}
+
+// a syscall supplied by the embedding application, it sees the calling thread just like the builtin ones.
+// its group decides which --deny-<group> flag turns it off, one without a group is always allowed.
+#[derive(Clone)]
+pub struct HostSysCall {
+    pub group: Option<SysCallGroup>,
+    pub call: Arc<dyn Fn(&mut VirtualThread) + Send + Sync>,
+}
+
 pub struct VirtualMachine {
     pub rules: [fn(&mut crate::virtual_thread::VirtualThread) -> (); Instructions::__END__ as usize],
     pub syscalls: [fn(&mut crate::virtual_thread::VirtualThread) -> (); SysCalls::__END__ as usize],
     pub instructions: Vec<u8>,
-    pub threads: Vec<std::thread::JoinHandle<()>>,
+    // what each opcode reads after it, so a thread can check an instruction before running it.
+    pub operands: [&'static [Operand]; Instructions::__END__ as usize],
+    pub tls_template: Vec<u8>,
+    // the blocks handed out by MemoryAllocate, by host address, so guest addresses into them can be checked.
+    pub allocations: Mutex<BTreeMap<u64, usize>>,
+    pub clock: Clock,
+    pub interrupts: Arc<InterruptController>,
+    pub cancelled: Arc<AtomicBool>,
+    pub instruction_budget: Option<u64>,
+    pub deadline: Option<Instant>,
+    pub handles: Mutex<HandleTable>,
+    pub working_directory: PathBuf,
+    pub arguments: Vec<String>,
+    pub permissions: Permissions,
+    pub host_syscalls: HashMap<String, HostSysCall>,
+    pub host_imports: Vec<(String, u8)>,
+    pub host_table: HashMap<u8, HostSysCall>,
+    pub threads: Vec<std::thread::JoinHandle<ExitReason>>,
+}
+
+// lets the host reach a machine that is currently blocked in run() from another thread.
+#[derive(Clone)]
+pub struct VmHandle {
+    interrupts: Arc<InterruptController>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl VmHandle {
+    pub fn raise_interrupt(&self, line: u8) {
+        self.interrupts.raise(line);
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
 }
 
 impl VirtualMachine {
@@ -32,33 +116,141 @@ impl VirtualMachine {
         let rules = Self::get_rules();
         let syscalls = Self::get_syscalls();
         std::env::set_current_dir(base);
+        let working_directory = std::env::current_dir().unwrap_or_default();
         Self {
             rules,
             syscalls,
             instructions: std::mem::take(data),
+            operands: Self::get_operands(),
+            tls_template: vec![],
+            allocations: Mutex::new(BTreeMap::new()),
+            clock: Clock::host(),
+            interrupts: Arc::new(InterruptController::default()),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            instruction_budget: None,
+            deadline: None,
+            handles: Mutex::new(HandleTable::new()),
+            working_directory,
+            arguments: vec![],
+            permissions: Permissions::default(),
+            host_syscalls: HashMap::new(),
+            host_imports: vec![],
+            host_table: HashMap::new(),
             threads: vec![],
         }
     }
 
-    pub fn run(&mut self) {
-        let thread = VirtualThread::new(self, 0, "Main".to_string());
-        thread.join().unwrap();
+    pub fn handle(&self) -> VmHandle {
+        VmHandle {
+            interrupts: self.interrupts.clone(),
+            cancelled: self.cancelled.clone(),
+        }
+    }
+
+    // stops every thread at its next instruction boundary.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    // applies to each thread separately, counted from when the thread starts.
+    pub fn set_instruction_budget(&mut self, budget: Option<u64>) {
+        self.instruction_budget = budget;
+    }
+
+    // checked between instructions and while Sleep, ProcessWait or SocketTcpAccept wait, a syscall that blocks
+    // reading stdin, a pipe or a socket only notices it once the call returns.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    pub fn use_virtual_clock(&mut self) -> VirtualClock {
+        let clock = VirtualClock::default();
+        self.clock = Clock::Virtual(clock.clone());
+        clock
+    }
+
+    // the first argument is conventionally the program's own path.
+    pub fn set_arguments(&mut self, arguments: Vec<String>) {
+        self.arguments = arguments;
+    }
+
+    pub fn set_permissions(&mut self, permissions: Permissions) {
+        self.permissions = permissions;
+    }
+
+    // programs reach it through a `.syscall name;` declaration and `SysCall name;`.
+    pub fn register_syscall(&mut self, name: &str, group: Option<SysCallGroup>, syscall: impl Fn(&mut VirtualThread) + Send + Sync + 'static) {
+        self.host_syscalls.insert(name.to_string(), HostSysCall { group, call: Arc::new(syscall) });
+    }
+
+    pub fn load_host_syscalls(&mut self, imports: &mut Vec<(String, u8)>) {
+        self.host_imports = std::mem::take(imports);
+    }
+
+    // ids the program declared but nobody registered stay unbound and fault when called.
+    fn bind_host_syscalls(&mut self) {
+        self.host_table = self.host_imports.iter()
+            .filter_map(|(name, id)| Some((*id, self.host_syscalls.get(name)?.clone())))
+            .collect();
+    }
+
+    pub fn load_thread_local(&mut self, template: &mut Vec<u8>) {
+        self.tls_template = std::mem::take(template);
+    }
+
+    pub fn run(&mut self) -> ExitReason {
+        self.bind_host_syscalls();
+        let thread = VirtualThread::new(self, 0, "Main".to_string(), true);
+        thread.join().unwrap()
     }
 
     pub fn spawn(&mut self, start: u64) {
-        let thread = VirtualThread::new(self, start, "Worker".to_string());
+        let thread = VirtualThread::new(self, start, "Worker".to_string(), false);
         self.threads.push(thread);
     }
 
+    pub fn get_operands() -> [&'static [Operand]; Instructions::__END__ as usize] {
+        std::array::from_fn(|opcode| Instructions::from_opcode(opcode as u16).map_or(&[][..], Instructions::operands))
+    }
+
     pub fn get_rules() -> [fn(&mut VirtualThread) -> (); Instructions::__END__ as usize] {
         use arsenal_globals::Instructions::*;
 
         let mut rules = [(|_|{}) as fn(&mut VirtualThread) -> (); __END__ as usize];
-        rules[Halt as usize] = |thread| { thread.running = false; };
+        rules[Halt as usize] = |thread| { thread.stop(ExitReason::Halted(0)); };
+        rules[HaltRegister as usize] = |thread| {
+            let register = thread.last::<u8>() & 0x0f;
+            thread.stop(ExitReason::Halted(thread.registers[register as usize] as u8));
+        };
+        rules[HaltImmediate as usize] = |thread| {
+            let code = thread.last::<u8>();
+            thread.stop(ExitReason::Halted(code));
+        };
+        rules[InterruptReturn as usize] = |thread| {
+            let Some(frame) = thread.interrupt_frame.take() else {
+                return thread.fault(FaultKind::InterruptReturnOutsideHandler);
+            };
+            thread.registers = frame.registers;
+            thread.alu_flags = frame.alu_flags;
+        };
         rules[SysCall as usize] = |thread| {
             let call_id = thread.last::<u8>();
-            assert!(((call_id as usize) < __END__ as usize), "syscall of id {call_id} does not exist.");
-            thread.parent.as_ref().syscalls[call_id as usize](thread);
+            let host = thread.parent.host_table.get(&call_id).cloned();
+            if call_id as usize >= SysCalls::__END__ as usize && host.is_none() {
+                return thread.fault(FaultKind::InvalidSysCall(call_id));
+            }
+            let permissions = &thread.parent.permissions;
+            // a path that is not valid text is left for the syscall itself to reject.
+            let paths_allowed = path_registers(call_id).iter()
+                .all(|&register| guest_path(thread, thread.registers[register]).map_or(true, |path| permissions.allows_path(&path)));
+            let group = host.as_ref().map_or(SysCallGroup::of(call_id), |host| host.group);
+            if !permissions.allows_group(group) || !paths_allowed {
+                return thread.fault(FaultKind::PermissionDenied(call_id));
+            }
+            match host {
+                Some(host) => (host.call)(thread),
+                None => thread.parent.as_ref().syscalls[call_id as usize](thread),
+            }
         };
         rules[LoadRegisterByte as usize] = |thread| {
             let register_id = thread.last::<u8>();
@@ -394,169 +586,209 @@ impl VirtualMachine {
         rules[PushRegisterByte as usize] = |thread| {
             let register = thread.last::<u8>() & 0x0f;
             let reg_val = thread.registers[register as usize];
-            push_byte_stack(&mut thread.stack, &mut thread.registers[RegisterRoles::StackPointer as usize], reg_val as u8);
+            push(thread, reg_val as u8);
         };
         rules[PushRegisterShort as usize] = |thread| {
             let register = thread.last::<u8>() & 0x0f;
             let reg_val = thread.registers[register as usize];
-            push_stack(&mut thread.stack, &mut thread.registers[RegisterRoles::StackPointer as usize], reg_val as u16);
+            push(thread, reg_val as u16);
         };
         rules[PushRegisterInt as usize] = |thread| {
             let register = thread.last::<u8>() & 0x0f;
             let reg_val = thread.registers[register as usize];
-            push_stack(&mut thread.stack, &mut thread.registers[RegisterRoles::StackPointer as usize], reg_val as u32);
+            push(thread, reg_val as u32);
         };
         rules[PushRegisterLong as usize] = |thread| {
             let register = thread.last::<u8>() & 0x0f;
             let reg_val = thread.registers[register as usize];
-            push_stack(&mut thread.stack, &mut thread.registers[RegisterRoles::StackPointer as usize], reg_val);
+            push(thread, reg_val);
         };
         rules[PopRegisterByte as usize] = |thread| {
             let register = thread.last::<u8>() & 0x0f;
-            unsafe {
-                let location = (&mut thread.registers[register as usize]) as *mut u64 as *mut u8 as usize;
-                pop_byte_stack(&mut thread.stack, &mut thread.registers[RegisterRoles::StackPointer as usize], &mut *(location as *mut u8));
+            if let Some(value) = pop::<u8>(thread) {
+                unsafe {
+                    write_unaligned(&mut thread.registers[register as usize] as *mut _ as *mut u8, value);
+                }
             }
         };
         rules[PopRegisterShort as usize] = |thread| {
             let register = thread.last::<u8>() & 0x0f;
-            unsafe {
-                let location = &mut thread.registers[register as usize] as *mut _ as *mut u16;
-                pop_stack(&mut thread.stack, &mut thread.registers[RegisterRoles::StackPointer as usize], &mut *location);
+            if let Some(value) = pop::<u16>(thread) {
+                unsafe {
+                    write_unaligned(&mut thread.registers[register as usize] as *mut _ as *mut u16, value);
+                }
             }
         };
         rules[PopRegisterInt as usize] = |thread| {
             let register = thread.last::<u8>() & 0x0f;
-            unsafe {
-                let location = &mut thread.registers[register as usize] as *mut _ as *mut u32;
-                pop_stack(&mut thread.stack, &mut thread.registers[RegisterRoles::StackPointer as usize], &mut *location);
+            if let Some(value) = pop::<u32>(thread) {
+                unsafe {
+                    write_unaligned(&mut thread.registers[register as usize] as *mut _ as *mut u32, value);
+                }
             }
         };
         rules[PopRegisterLong as usize] = |thread| {
             let register = thread.last::<u8>() & 0x0f;
-            unsafe {
-                let location = &mut thread.registers[register as usize] as *mut _ as *mut u64;
-                pop_stack(&mut thread.stack, &mut thread.registers[RegisterRoles::StackPointer as usize], &mut *location);
+            if let Some(value) = pop::<u64>(thread) {
+                unsafe {
+                    write_unaligned(&mut thread.registers[register as usize] as *mut _ as *mut u64, value);
+                }
             }
         };
         rules[MoveMemoryRegisterByte as usize] = |thread| {
             let address = thread.last::<u64>();
             let register = thread.last::<u8>() & 0x0f;
-            let base_pointer = &thread.parent.as_ref().instructions[0] as *const u8 as u64;
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u8>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
             unsafe {
-                write_unaligned(&mut thread.registers[register as usize] as *mut _ as *mut u8, read_unaligned((base_pointer.wrapping_add(address)) as *const u8));
+                write_unaligned(&mut thread.registers[register as usize] as *mut _ as *mut u8, read_unaligned(memory as *const u8));
             }
         };
         rules[MoveMemoryRegisterShort as usize] = |thread| {
             let address = thread.last::<u64>();
             let register = thread.last::<u8>() & 0x0f;
-            let base_pointer = &thread.parent.as_ref().instructions[0] as *const u8 as u64;
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u16>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
             unsafe {
-                write_unaligned(&mut thread.registers[register as usize] as *mut _ as *mut u16, read_unaligned((base_pointer.wrapping_add(address)) as *const u16));
+                write_unaligned(&mut thread.registers[register as usize] as *mut _ as *mut u16, read_unaligned(memory as *const u16));
             }
         };
         rules[MoveMemoryRegisterInt as usize] = |thread| {
             let address = thread.last::<u64>();
             let register = thread.last::<u8>() & 0x0f;
-            let base_pointer = &thread.parent.as_ref().instructions[0] as *const u8 as u64;
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u32>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
             unsafe {
-                write_unaligned(&mut thread.registers[register as usize] as *mut _ as *mut u32, read_unaligned((base_pointer.wrapping_add(address)) as *const u32));
+                write_unaligned(&mut thread.registers[register as usize] as *mut _ as *mut u32, read_unaligned(memory as *const u32));
             }
         };
         rules[MoveMemoryRegisterLong as usize] = |thread| {
             let address = thread.last::<u64>();
             let register = thread.last::<u8>() & 0x0f;
-            let base_pointer = &thread.parent.as_ref().instructions[0] as *const u8 as u64;
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u64>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
             unsafe {
-                write_unaligned(&mut thread.registers[register as usize] as *mut _ as *mut u64, read_unaligned((base_pointer.wrapping_add(address)) as *const u64));
+                write_unaligned(&mut thread.registers[register as usize] as *mut _ as *mut u64, read_unaligned(memory as *const u64));
             }
         };
         rules[MoveRegisterMemoryByte as usize] = |thread| {
             let register = thread.last::<u8>() & 0x0f;
             let address = thread.last::<u64>();
-            let base_pointer = &thread.parent.as_ref().instructions[0] as *const u8 as u64;
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u8>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
             unsafe {
-                write_unaligned((base_pointer.wrapping_add(address)) as *mut u8, read_unaligned(&mut thread.registers[register as usize] as *mut _ as *mut u8));
+                write_unaligned(memory as *mut u8, read_unaligned(&mut thread.registers[register as usize] as *mut _ as *mut u8));
             }
         };
         rules[MoveRegisterMemoryShort as usize] = |thread| {
             let register = thread.last::<u8>() & 0x0f;
             let address = thread.last::<u64>();
-            let base_pointer = &thread.parent.as_ref().instructions[0] as *const u8 as u64;
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u16>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
             unsafe {
-                write_unaligned((base_pointer.wrapping_add(address)) as *mut u16, read_unaligned(&mut thread.registers[register as usize] as *mut _ as *mut u16));
+                write_unaligned(memory as *mut u16, read_unaligned(&mut thread.registers[register as usize] as *mut _ as *mut u16));
             }
         };
         rules[MoveRegisterMemoryInt as usize] = |thread| {
             let register = thread.last::<u8>() & 0x0f;
             let address = thread.last::<u64>();
-            let base_pointer = &thread.parent.as_ref().instructions[0] as *const u8 as u64;
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u32>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
             unsafe {
-                write_unaligned((base_pointer.wrapping_add(address)) as *mut u32, read_unaligned(&mut thread.registers[register as usize] as *mut _ as *mut u32));
+                write_unaligned(memory as *mut u32, read_unaligned(&mut thread.registers[register as usize] as *mut _ as *mut u32));
             }
         };
         rules[MoveRegisterMemoryLong as usize] = |thread| {
             let register = thread.last::<u8>() & 0x0f;
             let address = thread.last::<u64>();
-            let base_pointer = &thread.parent.as_ref().instructions[0] as *const u8 as u64;
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u64>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
             unsafe {
-                write_unaligned((base_pointer.wrapping_add(address)) as *mut u64, read_unaligned(&mut thread.registers[register as usize] as *mut _ as *mut u64));
+                write_unaligned(memory as *mut u64, read_unaligned(&mut thread.registers[register as usize] as *mut _ as *mut u64));
             }
         };
         rules[PushMemoryByte as usize] = |thread| {
             let address = thread.last::<u64>();
-            unsafe {
-                let data = read_unaligned((&thread.parent.as_ref().instructions[0] as *const _ as u64).wrapping_add(address) as *const u64);
-                push_byte_stack(&mut thread.stack, &mut thread.registers[RegisterRoles::StackPointer as usize], data as u8);
-            }
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u8>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
+            let data = unsafe { read_unaligned(memory as *const u8) };
+            push(thread, data);
         };
         rules[PushMemoryShort as usize] = |thread| {
             let address = thread.last::<u64>();
-            unsafe {
-                let data = read_unaligned((&thread.parent.as_ref().instructions[0] as *const _ as u64).wrapping_add(address) as *const u64);
-                push_stack(&mut thread.stack, &mut thread.registers[RegisterRoles::StackPointer as usize], data as u16);
-            }
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u16>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
+            let data = unsafe { read_unaligned(memory as *const u16) };
+            push(thread, data);
         };
         rules[PushMemoryInt as usize] = |thread| {
             let address = thread.last::<u64>();
-            unsafe {
-                let data = read_unaligned((&thread.parent.as_ref().instructions[0] as *const _ as u64).wrapping_add(address) as *const u64);
-                push_stack(&mut thread.stack, &mut thread.registers[RegisterRoles::StackPointer as usize], data as u32);
-            }
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u32>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
+            let data = unsafe { read_unaligned(memory as *const u32) };
+            push(thread, data);
         };
         rules[PushMemoryLong as usize] = |thread| {
             let address = thread.last::<u64>();
-            unsafe {
-                let data = read_unaligned((&thread.parent.as_ref().instructions[0] as *const _ as u64).wrapping_add(address) as *const u64);
-                push_stack(&mut thread.stack, &mut thread.registers[RegisterRoles::StackPointer as usize], data as u64);
-            }
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u64>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
+            let data = unsafe { read_unaligned(memory as *const u64) };
+            push(thread, data);
         };
         rules[PopMemoryByte as usize] = |thread| {
             let address = thread.last::<u64>();
-            unsafe {
-                let address = ((&thread.parent.as_ref().instructions[0] as *const _ as u64).wrapping_add(address) as *mut u8);
-                pop_stack(&mut thread.stack, &mut thread.registers[RegisterRoles::StackPointer as usize], &mut read_unaligned(address));
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u8>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
+            if let Some(value) = pop::<u8>(thread) {
+                unsafe {
+                    write_unaligned(memory as *mut u8, value);
+                }
             }
         };
         rules[PopMemoryShort as usize] = |thread| {
             let address = thread.last::<u64>();
-            unsafe {
-                let address = ((&thread.parent.as_ref().instructions[0] as *const _ as u64).wrapping_add(address) as *mut u16);
-                pop_stack(&mut thread.stack, &mut thread.registers[RegisterRoles::StackPointer as usize], &mut read_unaligned(address));
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u16>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
+            if let Some(value) = pop::<u16>(thread) {
+                unsafe {
+                    write_unaligned(memory as *mut u16, value);
+                }
             }
         };
         rules[PopMemoryInt as usize] = |thread| {
             let address = thread.last::<u64>();
-            unsafe {
-                let address = ((&thread.parent.as_ref().instructions[0] as *const _ as u64).wrapping_add(address) as *mut u32);
-                pop_stack(&mut thread.stack, &mut thread.registers[RegisterRoles::StackPointer as usize], &mut read_unaligned(address));
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u32>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
+            if let Some(value) = pop::<u32>(thread) {
+                unsafe {
+                    write_unaligned(memory as *mut u32, value);
+                }
             }
         };
         rules[PopMemoryLong as usize] = |thread| {
             let address = thread.last::<u64>();
-            unsafe {
-                let address = ((&thread.parent.as_ref().instructions[0] as *const _ as u64).wrapping_add(address) as *mut u64);
-                pop_stack(&mut thread.stack, &mut thread.registers[RegisterRoles::StackPointer as usize], &mut read_unaligned(address));
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u64>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
+            if let Some(value) = pop::<u64>(thread) {
+                unsafe {
+                    write_unaligned(memory as *mut u64, value);
+                }
             }
         };
         rules[BitwiseAndRegistersByte as usize] = |thread| {
@@ -782,97 +1014,145 @@ impl VirtualMachine {
         rules[MoveAddressedRegisterRegisterByte as usize] = |thread| {
             let registers = thread.last::<u8>();
             let (r1, r2) = ((registers & 0xf0) >> 4, (registers & 0x0f));
-            let (val1, val2) = (&mut thread.registers[r1 as usize] as *mut u64, &mut thread.registers[r2 as usize] as *mut u64);
+            let address = thread.registers[r1 as usize];
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u8>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
             unsafe {
-                write_unaligned(val2 as *mut u8, read_unaligned((read_unaligned(val1).wrapping_add(base(thread))) as *const u8));
+                write_unaligned(&mut thread.registers[r2 as usize] as *mut _ as *mut u8, read_unaligned(memory as *const u8));
             }
         };
         rules[MoveAddressedRegisterRegisterShort as usize] = |thread| {
             let registers = thread.last::<u8>();
             let (r1, r2) = ((registers & 0xf0) >> 4, (registers & 0x0f));
-            let (val1, val2) = (&mut thread.registers[r1 as usize] as *mut u64, &mut thread.registers[r2 as usize] as *mut u64);
+            let address = thread.registers[r1 as usize];
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u16>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
             unsafe {
-                write_unaligned(val2 as *mut u16, read_unaligned((read_unaligned(val1).wrapping_add(base(thread))) as *const u16));
+                write_unaligned(&mut thread.registers[r2 as usize] as *mut _ as *mut u16, read_unaligned(memory as *const u16));
             }
         };
         rules[MoveAddressedRegisterRegisterInt as usize] = |thread| {
             let registers = thread.last::<u8>();
             let (r1, r2) = ((registers & 0xf0) >> 4, (registers & 0x0f));
-            let (val1, val2) = (&mut thread.registers[r1 as usize] as *mut u64, &mut thread.registers[r2 as usize] as *mut u64);
+            let address = thread.registers[r1 as usize];
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u32>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
             unsafe {
-                write_unaligned(val2 as *mut u32, read_unaligned((read_unaligned(val1).wrapping_add(base(thread))) as *const u32));
+                write_unaligned(&mut thread.registers[r2 as usize] as *mut _ as *mut u32, read_unaligned(memory as *const u32));
             }
         };
         rules[MoveAddressedRegisterRegisterLong as usize] = |thread| {
             let registers = thread.last::<u8>();
             let (r1, r2) = ((registers & 0xf0) >> 4, (registers & 0x0f));
-            let (val1, val2) = (&mut thread.registers[r1 as usize] as *mut u64, &mut thread.registers[r2 as usize] as *mut u64);
+            let address = thread.registers[r1 as usize];
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u64>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
             unsafe {
-                write_unaligned(val2 as *mut u64, read_unaligned((read_unaligned(val1).wrapping_add(base(thread))) as *const u64));
+                write_unaligned(&mut thread.registers[r2 as usize] as *mut _ as *mut u64, read_unaligned(memory as *const u64));
             }
         };
         rules[MoveRegisterAddressedRegisterByte as usize] = |thread| {
             let registers = thread.last::<u8>();
             let (r1, r2) = ((registers & 0xf0) >> 4, (registers & 0x0f));
-            let (val1, val2) = (&mut thread.registers[r1 as usize] as *mut u64, &mut thread.registers[r2 as usize] as *mut u64);
+            let address = thread.registers[r2 as usize];
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u8>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
             unsafe {
-                write_unaligned((read_unaligned(val2).wrapping_add(base(thread))) as *mut u8, read_unaligned(val1 as *const u8));
+                write_unaligned(memory as *mut u8, thread.registers[r1 as usize] as u8);
             }
         };
         rules[MoveRegisterAddressedRegisterShort as usize] = |thread| {
             let registers = thread.last::<u8>();
             let (r1, r2) = ((registers & 0xf0) >> 4, (registers & 0x0f));
-            let (val1, val2) = (&mut thread.registers[r1 as usize] as *mut u64, &mut thread.registers[r2 as usize] as *mut u64);
+            let address = thread.registers[r2 as usize];
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u16>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
             unsafe {
-                write_unaligned((read_unaligned(val2).wrapping_add(base(thread))) as *mut u16, read_unaligned(val1 as *const u16));
+                write_unaligned(memory as *mut u16, thread.registers[r1 as usize] as u16);
             }
         };
         rules[MoveRegisterAddressedRegisterInt as usize] = |thread| {
             let registers = thread.last::<u8>();
             let (r1, r2) = ((registers & 0xf0) >> 4, (registers & 0x0f));
-            let (val1, val2) = (&mut thread.registers[r1 as usize] as *mut u64, &mut thread.registers[r2 as usize] as *mut u64);
+            let address = thread.registers[r2 as usize];
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u32>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
             unsafe {
-                write_unaligned((read_unaligned(val2).wrapping_add(base(thread))) as *mut u32, read_unaligned(val1 as *const u32));
+                write_unaligned(memory as *mut u32, thread.registers[r1 as usize] as u32);
             }
         };
         rules[MoveRegisterAddressedRegisterLong as usize] = |thread| {
             let registers = thread.last::<u8>();
             let (r1, r2) = ((registers & 0xf0) >> 4, (registers & 0x0f));
-            let (val1, val2) = (&mut thread.registers[r1 as usize] as *mut u64, &mut thread.registers[r2 as usize] as *mut u64);
+            let address = thread.registers[r2 as usize];
+            let Some(memory) = guest_pointer(thread, address, std::mem::size_of::<u64>()) else {
+                return thread.fault(FaultKind::InvalidAddress(address));
+            };
             unsafe {
-                write_unaligned((read_unaligned(val2).wrapping_add(base(thread))) as *mut u64, read_unaligned(val1 as *const u64));
+                write_unaligned(memory as *mut u64, thread.registers[r1 as usize] as u64);
             }
         };
         rules[MoveAddressedRegistersByte as usize] = |thread| {
             let registers = thread.last::<u8>();
             let (r1, r2) = ((registers & 0xf0) >> 4, (registers & 0x0f));
-            let (val1, val2) = (&mut thread.registers[r1 as usize] as *mut u64, &mut thread.registers[r2 as usize] as *mut u64);
+            let (from, to) = (thread.registers[r1 as usize], thread.registers[r2 as usize]);
+            let Some(source) = guest_pointer(thread, from, std::mem::size_of::<u8>()) else {
+                return thread.fault(FaultKind::InvalidAddress(from));
+            };
+            let Some(destination) = guest_pointer(thread, to, std::mem::size_of::<u8>()) else {
+                return thread.fault(FaultKind::InvalidAddress(to));
+            };
             unsafe {
-                write_unaligned((read_unaligned(val2).wrapping_add(base(thread))) as *mut u8, read_unaligned((read_unaligned(val1) + base(thread)) as *const u8));
+                write_unaligned(destination as *mut u8, read_unaligned(source as *const u8));
             }
         };
         rules[MoveAddressedRegistersShort as usize] = |thread| {
             let registers = thread.last::<u8>();
             let (r1, r2) = ((registers & 0xf0) >> 4, (registers & 0x0f));
-            let (val1, val2) = (&mut thread.registers[r1 as usize] as *mut u64, &mut thread.registers[r2 as usize] as *mut u64);
+            let (from, to) = (thread.registers[r1 as usize], thread.registers[r2 as usize]);
+            let Some(source) = guest_pointer(thread, from, std::mem::size_of::<u16>()) else {
+                return thread.fault(FaultKind::InvalidAddress(from));
+            };
+            let Some(destination) = guest_pointer(thread, to, std::mem::size_of::<u16>()) else {
+                return thread.fault(FaultKind::InvalidAddress(to));
+            };
             unsafe {
-                write_unaligned((read_unaligned(val2).wrapping_add(base(thread))) as *mut u16, read_unaligned((read_unaligned(val1) + base(thread)) as *const u16));
+                write_unaligned(destination as *mut u16, read_unaligned(source as *const u16));
             }
         };
         rules[MoveAddressedRegistersInt as usize] = |thread| {
             let registers = thread.last::<u8>();
             let (r1, r2) = ((registers & 0xf0) >> 4, (registers & 0x0f));
-            let (val1, val2) = (&mut thread.registers[r1 as usize] as *mut u64, &mut thread.registers[r2 as usize] as *mut u64);
+            let (from, to) = (thread.registers[r1 as usize], thread.registers[r2 as usize]);
+            let Some(source) = guest_pointer(thread, from, std::mem::size_of::<u32>()) else {
+                return thread.fault(FaultKind::InvalidAddress(from));
+            };
+            let Some(destination) = guest_pointer(thread, to, std::mem::size_of::<u32>()) else {
+                return thread.fault(FaultKind::InvalidAddress(to));
+            };
             unsafe {
-                write_unaligned((read_unaligned(val2).wrapping_add(base(thread))) as *mut u32, read_unaligned((read_unaligned(val1) + base(thread)) as *const u32));
+                write_unaligned(destination as *mut u32, read_unaligned(source as *const u32));
             }
         };
         rules[MoveAddressedRegistersLong as usize] = |thread| {
             let registers = thread.last::<u8>();
             let (r1, r2) = ((registers & 0xf0) >> 4, (registers & 0x0f));
-            let (val1, val2) = (&mut thread.registers[r1 as usize] as *mut u64, &mut thread.registers[r2 as usize] as *mut u64);
+            let (from, to) = (thread.registers[r1 as usize], thread.registers[r2 as usize]);
+            let Some(source) = guest_pointer(thread, from, std::mem::size_of::<u64>()) else {
+                return thread.fault(FaultKind::InvalidAddress(from));
+            };
+            let Some(destination) = guest_pointer(thread, to, std::mem::size_of::<u64>()) else {
+                return thread.fault(FaultKind::InvalidAddress(to));
+            };
             unsafe {
-                write_unaligned((read_unaligned(val2).wrapping_add(base(thread))) as *mut u64, read_unaligned((read_unaligned(val1) + base(thread)) as *const u64));
+                write_unaligned(destination as *mut u64, read_unaligned(source as *const u64));
             }
         };
         rules
@@ -881,73 +1161,341 @@ impl VirtualMachine {
         use arsenal_globals::SysCalls::*;
 
         let mut syscalls = [(|_|{}) as fn(&mut VirtualThread) -> (); __END__ as usize];
+        use std::os::raw::c_void;
         syscalls[PrintRegister as usize] = |thread| {
-            print!("{}", thread.registers[thread.registers[0] as usize]);
+            let Some(value) = guest_register(thread, thread.registers[0]) else { return };
+            let _ = write!(guest_stdout(), "{}", value);
         };
         syscalls[PrintRegisterSigned as usize] = |thread| {
-            print!("{}", thread.registers[thread.registers[0] as usize] as i64);
+            let Some(value) = guest_register(thread, thread.registers[0]) else { return };
+            let _ = write!(guest_stdout(), "{}", value as i64);
+        };
+        syscalls[PrintRegisterHex as usize] = |thread| {
+            let Some(value) = guest_register(thread, thread.registers[0]) else { return };
+            let _ = write!(guest_stdout(), "{:x}", value);
+        };
+        syscalls[PrintRegisterBinary as usize] = |thread| {
+            let Some(value) = guest_register(thread, thread.registers[0]) else { return };
+            let _ = write!(guest_stdout(), "{:b}", value);
         };
         syscalls[PrintCString as usize] = |thread| {
-            unsafe {
-                use std::ffi::{CStr, c_char};
-                let ptr = (thread.registers[0].wrapping_add(&thread.parent.instructions[0] as *const _ as *const u8 as u64)) as usize as *const c_char;
-                print!("{}", CStr::from_ptr(ptr).to_str().expect(&format!("error parsing {}", *ptr)));
+            let Some(text) = guest_c_str(thread, thread.registers[0]) else {
+                return thread.fault(FaultKind::InvalidAddress(thread.registers[0]));
+            };
+            let _ = guest_stdout().write_all(text);
+        };
+        syscalls[PrintFormat as usize] = |thread| {
+            let Some(format) = guest_c_str(thread, thread.registers[0]) else {
+                return thread.fault(FaultKind::InvalidAddress(thread.registers[0]));
+            };
+            // the first argument or %s string that is not in guest memory, the text is only printed when there is none.
+            let invalid = std::cell::Cell::new(None);
+            let mut argument = thread.registers[1];
+            let next_argument = || {
+                let value = guest_pointer(thread, argument, 8).map(|slot| unsafe { read_unaligned(slot as *const u64) });
+                invalid.set(invalid.get().or(value.is_none().then_some(argument)));
+                argument = argument.wrapping_add(8);
+                value.unwrap_or(0)
+            };
+            let string_at = |address| {
+                let string = guest_c_str(thread, address);
+                invalid.set(invalid.get().or(string.is_none().then_some(address)));
+                string.unwrap_or_default().to_vec()
+            };
+            let text = crate::format::format(format, next_argument, string_at);
+            match invalid.get() {
+                Some(address) => thread.fault(FaultKind::InvalidAddress(address)),
+                None => { let _ = guest_stdout().write_all(&text); },
             }
         };
         syscalls[MemoryAllocate as usize] = |thread| {
             use std::alloc::{alloc, Layout};
             let size = thread.registers[0];
-            let layout = Layout::from_size_align(size.try_into().unwrap(), std::mem::align_of::<u8>()).unwrap();
-            unsafe {
-                thread.registers[0] = (alloc(layout) as u64).wrapping_sub(base(thread));
-            }
+            // an empty or impossibly large block is refused with -1.
+            let layout = usize::try_from(size).ok().filter(|size| *size > 0)
+                .and_then(|size| Layout::from_size_align(size, std::mem::align_of::<u8>()).ok());
+            let block = layout.map(|layout| unsafe { alloc(layout) }).filter(|block| !block.is_null());
+            thread.registers[0] = match block {
+                Some(block) => {
+                    thread.parent.allocations.lock().unwrap().insert(block as u64, size as usize);
+                    (block as u64).wrapping_sub(base(thread))
+                },
+                None => u64::MAX,
+            };
         };
         syscalls[MemoryFree as usize] = |thread| {
             use std::alloc::{dealloc, Layout};
-            let (ptr, size) = (thread.registers[0], thread.registers[1]);
-            let layout = Layout::from_size_align(size.try_into().unwrap(), std::mem::align_of::<u8>()).unwrap();
+            // the size is known from the allocation, only the start of a block the machine handed out can be freed.
+            let ptr = thread.registers[0];
+            let block = ptr.wrapping_add(base(thread));
+            let Some(size) = thread.parent.allocations.lock().unwrap().remove(&block) else {
+                return thread.fault(FaultKind::InvalidAddress(ptr));
+            };
             unsafe {
-                dealloc((ptr.wrapping_add(base(thread))) as *mut u8, layout);
+                dealloc(block as *mut u8, Layout::from_size_align_unchecked(size, std::mem::align_of::<u8>()));
             }
         };
         syscalls[FOpen as usize] = |thread| {
-            use std::ffi::c_char;
-            let ptr1 = (thread.registers[0].wrapping_add(base(thread))) as usize as *const c_char;
-            let ptr2 = (thread.registers[1].wrapping_add(base(thread))) as usize as *const c_char;
-            unsafe {
-                let ptr = libc::fopen(ptr1, ptr2);
-                thread.registers[0] = (ptr as u64).wrapping_sub(base(thread));
-            }
+            let file = guest_path(thread, thread.registers[0])
+                .and_then(|path| HostHandle::open_file(&path, guest_str(thread, thread.registers[1])?));
+            thread.registers[0] = match file {
+                Ok(file) => thread.parent.handles.lock().unwrap().insert(file),
+                Err(_) => u64::MAX,
+            };
         };
         syscalls[FClose as usize] = |thread| {
-            use std::ffi::c_char;
-            let ptr1 = (thread.registers[0].wrapping_add(&thread.parent.instructions[0] as *const _ as *const u8 as u64)) as usize;
-            unsafe {
-                let ptr = libc::fclose((ptr1 as u64) as *mut libc::FILE);
-            }
+            let handle = thread.parent.handles.lock().unwrap().remove(thread.registers[0]);
+            thread.registers[1] = guest_result(handle.and_then(|handle| handle.lock().unwrap().handle.close()).map(|_| 0));
         };
         syscalls[FGetC as usize] = |thread| {
-            use std::ffi::c_char;
-            let ptr1 = (thread.registers[0].wrapping_add(&thread.parent.instructions[0] as *const _ as *const u8 as u64)) as usize;
-            unsafe {
-                thread.registers[1] = libc::fgetc(ptr1 as *mut libc::FILE) as u64;
-            }
+            thread.registers[1] = with_handle(thread, |handle| Ok(handle.getc()?.map_or(u64::MAX, |byte| byte as u64)));
+        };
+        syscalls[FPutC as usize] = |thread| {
+            let byte = thread.registers[1] as u8;
+            thread.registers[1] = with_handle(thread, |handle| Ok(handle.putc(byte)? as u64));
+        };
+        syscalls[FRead as usize] = |thread| {
+            let buffer = guest_slice(thread, thread.registers[1], thread.registers[2]);
+            thread.registers[1] = with_handle(thread, |handle| Ok(handle.read(buffer?)? as u64));
+        };
+        syscalls[FWrite as usize] = |thread| {
+            let buffer = guest_slice(thread, thread.registers[1], thread.registers[2]);
+            thread.registers[1] = with_handle(thread, |handle| Ok(handle.write(buffer?)? as u64));
+        };
+        syscalls[FFlush as usize] = |thread| {
+            thread.registers[1] = with_handle(thread, |handle| handle.flush().map(|_| 0));
+        };
+        syscalls[FEof as usize] = |thread| {
+            thread.registers[1] = with_handle(thread, |handle| Ok(handle.eof()? as u64));
+        };
+        syscalls[FError as usize] = |thread| {
+            // the error kept on this handle, a stream in the error state with nothing recorded reports Other.
+            let handle = thread.parent.handles.lock().unwrap().get(thread.registers[0]);
+            let error = handle.and_then(|handle| {
+                let mut entry = handle.lock().unwrap();
+                Ok(match (entry.handle.error()?, entry.error) {
+                    (false, _) => ErrorCodes::Success,
+                    (true, ErrorCodes::Success) => ErrorCodes::Other,
+                    (true, error) => error,
+                } as u64)
+            });
+            thread.registers[1] = guest_result(error);
         };
         syscalls[FTell as usize] = |thread| {
-            use std::ffi::c_char;
-            let ptr1 = (thread.registers[0].wrapping_add(base(thread))) as usize;
-            unsafe {
-                thread.registers[1] = libc::ftell((ptr1 as u64) as *mut libc::FILE) as u64;
-            }
+            thread.registers[1] = with_handle(thread, |handle| handle.tell());
         };
         syscalls[FSeek as usize] = |thread| {
-            use std::ffi::c_char;
-            let ptr1 = (thread.registers[0].wrapping_add(&thread.parent.instructions[0] as *const _ as *const u8 as u64)) as usize;
-            let offset = thread.registers[1];
-            let whence = thread.registers[2];
-            unsafe {
-                thread.registers[1] = libc::fseek((ptr1 as u64) as *mut libc::FILE, offset as i64 as i32, whence as i32) as u64;
-            }
+            let (offset, whence) = (thread.registers[1] as i64, thread.registers[2] as i32);
+            thread.registers[1] = with_handle(thread, |handle| handle.seek(offset, whence).map(|_| 0));
+        };
+        syscalls[ReadLine as usize] = |thread| {
+            let buffer = guest_slice(thread, thread.registers[0], thread.registers[1]);
+            let result = buffer.and_then(|buffer| {
+                let line = read_stdin_line()?.ok_or(ErrorCodes::EndOfFile)?;
+                // the line is cut short to leave room for the null terminator.
+                let length = line.len().min(buffer.len().checked_sub(1).ok_or(ErrorCodes::InvalidArgument)?);
+                buffer[..length].copy_from_slice(&line.as_bytes()[..length]);
+                buffer[length] = 0;
+                Ok(length as u64)
+            });
+            thread.registers[0] = guest_result(result);
+        };
+        syscalls[ReadChar as usize] = |thread| {
+            let mut byte = [0];
+            let result = read_stdin(&mut byte).map(|read| if read == 1 { byte[0] as u64 } else { u64::MAX });
+            thread.registers[0] = guest_result(result);
+        };
+        syscalls[ReadInteger as usize] = |thread| {
+            let result = read_stdin_line().and_then(|line| {
+                let line = line.ok_or(ErrorCodes::EndOfFile)?;
+                line.trim().parse::<i64>().map_err(|_| ErrorCodes::InvalidArgument)
+            });
+            (thread.registers[0], thread.registers[1]) = match result {
+                Ok(value) => (value as u64, 0),
+                Err(_) => (0, u64::MAX),
+            };
+        };
+        syscalls[DirOpen as usize] = |thread| {
+            let directory = guest_path(thread, thread.registers[0]).and_then(|path| HostHandle::open_directory(&path));
+            thread.registers[0] = match directory {
+                Ok(directory) => thread.parent.handles.lock().unwrap().insert(directory),
+                Err(_) => u64::MAX,
+            };
+        };
+        syscalls[DirRead as usize] = |thread| {
+            let buffer = guest_slice(thread, thread.registers[1], thread.registers[2]);
+            let mut kind = FileKinds::Other;
+            thread.registers[1] = with_handle(thread, |handle| {
+                let buffer = buffer?;
+                // the name needs room for its null terminator.
+                let (name, entry_kind) = handle.read_directory(|name| name.len() < buffer.len())?.ok_or(ErrorCodes::EndOfFile)?;
+                buffer[..name.len()].copy_from_slice(name.as_bytes());
+                buffer[name.len()] = 0;
+                kind = entry_kind;
+                Ok(name.len() as u64)
+            });
+            thread.registers[2] = kind as u64;
+        };
+        syscalls[DirClose as usize] = |thread| {
+            let handle = thread.parent.handles.lock().unwrap().remove(thread.registers[0]);
+            thread.registers[1] = guest_result(handle.and_then(|handle| handle.lock().unwrap().handle.close()).map(|_| 0));
+        };
+        syscalls[FileStat as usize] = |thread| {
+            let stat = guest_path(thread, thread.registers[0]).and_then(|path| std::fs::metadata(path).map_err(io_error));
+            let result = stat.and_then(|stat| {
+                let modified = stat.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map_or(0, |time| time.as_nanos() as u64);
+                // size, modification time in nanoseconds since the unix epoch and kind, each 8 bytes.
+                let fields = [stat.len(), modified, FileKinds::from(stat.file_type()) as u64];
+                guest_slice(thread, thread.registers[1], 24)?.copy_from_slice(&fields.map(u64::to_ne_bytes).concat());
+                Ok(0)
+            });
+            thread.registers[1] = guest_result(result);
+        };
+        syscalls[FileRemove as usize] = |thread| {
+            let result = guest_path(thread, thread.registers[0]).and_then(|path| {
+                match std::fs::symlink_metadata(&path).map_err(io_error)?.is_dir() {
+                    true => std::fs::remove_dir(path),
+                    false => std::fs::remove_file(path),
+                }.map_err(io_error)
+            });
+            thread.registers[1] = guest_result(result.map(|_| 0));
+        };
+        syscalls[FileRename as usize] = |thread| {
+            let result = guest_path(thread, thread.registers[0])
+                .and_then(|from| Ok((from, guest_path(thread, thread.registers[1])?)))
+                .and_then(|(from, to)| std::fs::rename(from, to).map_err(io_error));
+            thread.registers[1] = guest_result(result.map(|_| 0));
+        };
+        syscalls[MakeDirectory as usize] = |thread| {
+            let result = guest_path(thread, thread.registers[0]).and_then(|path| std::fs::create_dir(path).map_err(io_error));
+            thread.registers[1] = guest_result(result.map(|_| 0));
+        };
+        syscalls[ArgCount as usize] = |thread| {
+            thread.registers[0] = thread.parent.arguments.len() as u64;
+        };
+        syscalls[ArgGet as usize] = |thread| {
+            let buffer = guest_slice(thread, thread.registers[1], thread.registers[2]);
+            let result = match thread.parent.arguments.get(thread.registers[0] as usize) {
+                Some(argument) => buffer.and_then(|buffer| write_guest_string(buffer, argument)),
+                None => Err(ErrorCodes::InvalidArgument),
+            };
+            thread.registers[0] = guest_result(result);
+        };
+        syscalls[EnvGet as usize] = |thread| {
+            let buffer = guest_slice(thread, thread.registers[1], thread.registers[2]);
+            let result = guest_str(thread, thread.registers[0])
+                .and_then(|name| std::env::var(name).map_err(|_| ErrorCodes::NotFound))
+                .and_then(|value| write_guest_string(buffer?, &value));
+            thread.registers[0] = guest_result(result);
+        };
+        syscalls[ProcessSpawn as usize] = |thread| {
+            use std::process::{Command, Stdio};
+            let (argv, count, flags) = (thread.registers[0], thread.registers[1], thread.registers[2]);
+            let arguments = guest_slice(thread, argv, count.saturating_mul(8)).and_then(|argv| {
+                argv.chunks_exact(8).map(|address| guest_str(thread, u64::from_ne_bytes(address.try_into().unwrap()))).collect::<HandleResult<Vec<&str>>>()
+            });
+            let child = arguments.and_then(|arguments| {
+                let (program, arguments) = arguments.split_first().ok_or(ErrorCodes::InvalidArgument)?;
+                let pipe = |flag: SpawnFlags| if flags & flag as u64 != 0 { Stdio::piped() } else { Stdio::inherit() };
+                // anything the guest printed so far has to come out before the child's output.
+                crate::format::flush_guest_stdout().map_err(io_error)?;
+                Command::new(program)
+                    .args(arguments)
+                    .current_dir(&thread.parent.working_directory)
+                    .stdin(pipe(SpawnFlags::PipeStdin))
+                    .stdout(pipe(SpawnFlags::PipeStdout))
+                    .stderr(pipe(SpawnFlags::PipeStderr))
+                    .spawn()
+                    .map_err(io_error)
+            });
+            let mut child = match child {
+                Ok(child) => child,
+                Err(_) => {
+                    thread.registers[0] = u64::MAX;
+                    return;
+                },
+            };
+            let mut handles = thread.parent.handles.lock().unwrap();
+            let stdin = child.stdin.take().map_or(u64::MAX, |pipe| handles.insert(HostHandle::Writer(Box::new(pipe))));
+            let stdout = child.stdout.take().map_or(u64::MAX, |pipe| handles.insert(HostHandle::Reader { reader: Box::new(pipe), eof: false }));
+            let stderr = child.stderr.take().map_or(u64::MAX, |pipe| handles.insert(HostHandle::Reader { reader: Box::new(pipe), eof: false }));
+            let process = handles.insert(HostHandle::Process(ChildProcess(child)));
+            drop(handles);
+            thread.registers[0..4].copy_from_slice(&[process, stdin, stdout, stderr]);
+        };
+        syscalls[ProcessWait as usize] = |thread| {
+            thread.registers[1] = guest_result(poll_handle(thread, HostHandle::try_wait));
+        };
+        syscalls[SocketTcpConnect as usize] = |thread| {
+            let timeout = socket_timeout(thread.registers[1]);
+            let stream = guest_str(thread, thread.registers[0]).and_then(|address| HostHandle::tcp_connect(address, timeout));
+            thread.registers[0] = match stream {
+                Ok(stream) => thread.parent.handles.lock().unwrap().insert(stream),
+                Err(_) => u64::MAX,
+            };
+        };
+        syscalls[SocketTcpListen as usize] = |thread| {
+            let listener = guest_str(thread, thread.registers[0]).and_then(HostHandle::tcp_listen);
+            thread.registers[0] = match listener {
+                Ok(listener) => thread.parent.handles.lock().unwrap().insert(listener),
+                Err(_) => u64::MAX,
+            };
+        };
+        syscalls[SocketTcpAccept as usize] = |thread| {
+            let stream = poll_handle(thread, HostHandle::try_accept);
+            thread.registers[0] = match stream {
+                Ok(stream) => thread.parent.handles.lock().unwrap().insert(stream),
+                Err(_) => u64::MAX,
+            };
+        };
+        syscalls[SocketUdpBind as usize] = |thread| {
+            let socket = guest_str(thread, thread.registers[0]).and_then(HostHandle::udp_bind);
+            thread.registers[0] = match socket {
+                Ok(socket) => thread.parent.handles.lock().unwrap().insert(socket),
+                Err(_) => u64::MAX,
+            };
+        };
+        syscalls[SocketSendTo as usize] = |thread| {
+            let buffer = guest_slice(thread, thread.registers[1], thread.registers[2]);
+            let address = guest_str(thread, thread.registers[3]);
+            thread.registers[1] = with_handle(thread, |handle| Ok(handle.send_to(buffer?, address?)? as u64));
+        };
+        syscalls[SocketRecvFrom as usize] = |thread| {
+            let buffer = guest_slice(thread, thread.registers[1], thread.registers[2]);
+            // the sender's address is only written back when a buffer for it was passed.
+            let sender = guest_slice(thread, thread.registers[3], thread.registers[4]);
+            thread.registers[1] = with_handle(thread, |handle| {
+                let (buffer, sender) = (buffer?, sender?);
+                let (received, address) = handle.recv_from(buffer)?;
+                if !sender.is_empty() {
+                    write_guest_string(sender, &address.to_string())?;
+                }
+                Ok(received as u64)
+            });
+        };
+        syscalls[SocketSend as usize] = |thread| {
+            let buffer = guest_slice(thread, thread.registers[1], thread.registers[2]);
+            thread.registers[1] = with_handle(thread, |handle| Ok(handle.send(buffer?)? as u64));
+        };
+        syscalls[SocketRecv as usize] = |thread| {
+            let buffer = guest_slice(thread, thread.registers[1], thread.registers[2]);
+            thread.registers[1] = with_handle(thread, |handle| Ok(handle.recv(buffer?)? as u64));
+        };
+        syscalls[SocketSetTimeout as usize] = |thread| {
+            let timeout = socket_timeout(thread.registers[1]);
+            thread.registers[1] = with_handle(thread, |handle| handle.set_timeout(timeout).map(|_| 0));
+        };
+        syscalls[SocketClose as usize] = |thread| {
+            let mut handles = thread.parent.handles.lock().unwrap();
+            let socket = handles.get(thread.registers[0]).and_then(|handle| {
+                match handle.lock().unwrap().handle {
+                    HostHandle::TcpStream(_) | HostHandle::TcpListener { .. } | HostHandle::UdpSocket(_) => Ok(()),
+                    _ => Err(ErrorCodes::InvalidHandle),
+                }
+            });
+            let handle = socket.and_then(|_| handles.remove(thread.registers[0]));
+            drop(handles);
+            thread.registers[1] = guest_result(handle.and_then(|handle| handle.lock().unwrap().handle.close()).map(|_| 0));
         };
         syscalls[MapMemoryLocalGlobal as usize] = |thread| {
             thread.registers[0] = thread.registers[0].wrapping_add(base(thread));
@@ -955,7 +1503,7 @@ impl VirtualMachine {
         syscalls[MapMemoryGlobalLocal as usize] = |thread| {
             thread.registers[0] = thread.registers[0].wrapping_sub(base(thread));
         };
-        use std::os::raw::{c_void, c_char};
+        use std::os::raw::c_char;
         syscalls[LoadDLL as usize] = |thread| {
             let name_ptr = thread.registers[0].wrapping_add(base(thread));
             let name = match unsafe { std::ffi::CStr::from_ptr(name_ptr as *const i8) }.to_str() {
@@ -1002,10 +1550,59 @@ impl VirtualMachine {
                 thread.registers[0] = dll_handler::call_c_function_args(func as *const std::os::raw::c_void, arguments_buffer as *const u8, arguments_count, data_buffer as *const u8) as u64;
             }
         };
+        syscalls[ThreadLocalBase as usize] = |thread| {
+            thread.registers[0] = (thread.tls.as_mut_ptr() as u64).wrapping_sub(base(thread));
+        };
+        syscalls[Sleep as usize] = |thread| {
+            let mut remaining = thread.registers[0];
+            while remaining > 0 && !thread.should_stop() {
+                remaining -= thread.parent.clock.sleep(remaining);
+            }
+        };
+        syscalls[ClockMonotonic as usize] = |thread| {
+            thread.registers[0] = thread.parent.clock.monotonic();
+        };
+        syscalls[ClockRealtime as usize] = |thread| {
+            thread.registers[0] = thread.parent.clock.realtime();
+        };
+        syscalls[CpuInstructionCount as usize] = |thread| {
+            thread.registers[0] = thread.instruction_count;
+        };
+        syscalls[InterruptRegister as usize] = |thread| {
+            let line = thread.registers[0];
+            if line as usize >= crate::interrupts::INTERRUPT_LINES {
+                return thread.fault(FaultKind::InvalidInterruptLine(line as u8));
+            }
+            thread.parent.interrupts.register(line as u8, thread.registers[1]);
+            thread.registers[0] = 0;
+        };
         syscalls
     }
 }
 
+// the stack grows as it is pushed to, a stack pointer moved further than this faults instead of allocating.
+const MAX_STACK: u64 = 16 << 20;
+
+fn push<T>(thread: &mut VirtualThread, data: T) {
+    let sp = thread.registers[RegisterRoles::StackPointer as usize];
+    if sp > MAX_STACK - std::mem::size_of::<T>() as u64 {
+        return thread.fault(FaultKind::StackPointerOutOfBounds(sp));
+    }
+    push_stack(&mut thread.stack, &mut thread.registers[RegisterRoles::StackPointer as usize], data);
+}
+
+// None after faulting when the stack holds less than a T below the stack pointer.
+fn pop<T: Default>(thread: &mut VirtualThread) -> Option<T> {
+    let sp = thread.registers[RegisterRoles::StackPointer as usize];
+    if sp < std::mem::size_of::<T>() as u64 || sp > thread.stack.len() as u64 {
+        thread.fault(FaultKind::StackPointerOutOfBounds(sp));
+        return None;
+    }
+    let mut value = T::default();
+    pop_stack(&mut thread.stack, &mut thread.registers[RegisterRoles::StackPointer as usize], &mut value);
+    Some(value)
+}
+
 fn push_byte_stack(stack: &mut Vec<u8>, sp: &mut u64, byte: u8) {
     while stack.len() <= (*sp).try_into().unwrap() {
         stack.push(0xff);
@@ -1045,6 +1642,119 @@ fn pop_stack<T>(stack: &mut Vec<u8>, sp: &mut u64, location: &mut T) {
     }
 }
 
+// the register a syscall argument names, faulting when there is no such register.
+fn guest_register(thread: &mut VirtualThread, register: u64) -> Option<u64> {
+    let value = thread.registers.get(register as usize).copied();
+    if value.is_none() {
+        thread.fault(FaultKind::InvalidRegister(register));
+    }
+    value
+}
+
+// how long a blocking call waits between looking at its handle again.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+// runs poll on the handle whose id is in r0 until it has an answer or the timeout set on the handle runs out.
+// the handle is only locked while poll runs, so other threads can use it in between,
+// and a cancelled machine or a passed deadline stops the thread as it waits.
+fn poll_handle<T>(thread: &mut VirtualThread, mut poll: impl FnMut(&mut HostHandle) -> HandleResult<Option<T>>) -> HandleResult<T> {
+    let handle = thread.parent.handles.lock().unwrap().get(thread.registers[0])?;
+    let start = Instant::now();
+    loop {
+        {
+            let mut entry = handle.lock().unwrap();
+            let result = match poll(&mut entry.handle) {
+                Ok(None) if entry.handle.timeout().is_some_and(|timeout| start.elapsed() >= timeout) => Err(ErrorCodes::TimedOut),
+                result => result,
+            };
+            if let Err(error) = result {
+                entry.error = error;
+            }
+            if let Some(value) = result.transpose() {
+                return value;
+            }
+        }
+        if thread.should_stop() {
+            return Err(ErrorCodes::Interrupted);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+// the guest memory from address to the end of the block it is in, the program, this thread's tls or a block from MemoryAllocate.
+fn guest_memory<'a>(thread: &VirtualThread, address: u64) -> Option<&'a mut [u8]> {
+    let start = address.wrapping_add(base(thread));
+    let program = &thread.parent.instructions;
+    let allocation = thread.parent.allocations.lock().unwrap().range(..=start).next_back().map(|(&block, &length)| (block, length));
+    [(program.as_ptr() as u64, program.len()), (thread.tls.as_ptr() as u64, thread.tls.len())].into_iter().chain(allocation)
+        .find(|&(block, length)| block <= start && start - block < length as u64)
+        .map(|(block, length)| unsafe { std::slice::from_raw_parts_mut(start as *mut u8, (block + length as u64 - start) as usize) })
+}
+
+fn guest_pointer(thread: &VirtualThread, address: u64, size: usize) -> Option<*mut std::ffi::c_void> {
+    guest_memory(thread, address).filter(|memory| memory.len() >= size).map(|memory| memory.as_mut_ptr().cast())
+}
+
+// the null-terminated bytes at address, the terminator has to be inside the same block.
+fn guest_c_str<'a>(thread: &VirtualThread, address: u64) -> Option<&'a [u8]> {
+    let memory = guest_memory(thread, address)?;
+    let length = memory.iter().position(|&byte| byte == 0)?;
+    Some(&memory[..length])
+}
+
+// failing calls hand -1 back to the guest, only an operation on a handle leaves an error behind for FError.
+fn guest_result(result: HandleResult<u64>) -> u64 {
+    result.unwrap_or(u64::MAX)
+}
+
+// runs an operation on the handle whose id is in r0, a failure is kept on the handle for FError.
+fn with_handle(thread: &mut VirtualThread, operation: impl FnOnce(&mut HostHandle) -> HandleResult<u64>) -> u64 {
+    let handle = thread.parent.handles.lock().unwrap().get(thread.registers[0]);
+    let result = handle.and_then(|handle| {
+        let mut entry = handle.lock().unwrap();
+        let result = operation(&mut entry.handle);
+        if let Err(error) = result {
+            entry.error = error;
+        }
+        result
+    });
+    guest_result(result)
+}
+
+fn guest_str<'a>(thread: &VirtualThread, address: u64) -> HandleResult<&'a str> {
+    let string = guest_c_str(thread, address).ok_or(ErrorCodes::InvalidAddress)?;
+    std::str::from_utf8(string).map_err(|_| ErrorCodes::InvalidArgument)
+}
+
+// relative paths are resolved against the machine's working directory, which is where the program was loaded from.
+fn guest_path(thread: &VirtualThread, address: u64) -> HandleResult<PathBuf> {
+    Ok(thread.parent.working_directory.join(guest_str(thread, address)?))
+}
+
+// copies text into a guest buffer with a null terminator, failing rather than cutting it short.
+fn write_guest_string(buffer: &mut [u8], text: &str) -> HandleResult<u64> {
+    if text.len() >= buffer.len() {
+        return Err(ErrorCodes::InvalidArgument);
+    }
+    buffer[..text.len()].copy_from_slice(text.as_bytes());
+    buffer[text.len()] = 0;
+    Ok(text.len() as u64)
+}
+
+// a timeout of zero milliseconds means the socket waits forever.
+fn socket_timeout(millis: u64) -> Option<Duration> {
+    (millis != 0).then(|| Duration::from_millis(millis))
+}
+
+// a guest buffer, which has to lie inside one block of guest memory.
+fn guest_slice<'a>(thread: &VirtualThread, address: u64, length: u64) -> HandleResult<&'a mut [u8]> {
+    if length == 0 {
+        return Ok(&mut []);
+    }
+    let memory = guest_memory(thread, address).filter(|memory| memory.len() as u64 >= length).ok_or(ErrorCodes::InvalidAddress)?;
+    Ok(&mut memory[..length as usize])
+}
+
 fn base(thread: &VirtualThread) -> u64 {
-    &thread.parent.as_ref().instructions[0] as *const _ as u64
+    thread.parent.as_ref().instructions.as_ptr() as u64
 }
@@ -1,34 +1,59 @@
 #![allow(unused)]
 
-use std::{thread, time::Duration, ptr::read_unaligned, sync::Arc};
+use std::{thread, time::{Duration, Instant}, ptr::read_unaligned, sync::{Arc, atomic::Ordering}, mem::ManuallyDrop};
 use crate::virtual_machine::*;
+use crate::interrupts::InterruptFrame;
+use arsenal_globals::Operand;
 
 pub struct VirtualThread {
-    pub parent: Arc<VirtualMachine>,
+    pub parent: ManuallyDrop<Arc<VirtualMachine>>,
     pub registers: [u64; 16],
     pub running: bool,
     pub alu_flags: u8,
     pub stack: Vec<u8>,
+    pub tls: Vec<u8>,
+    pub instruction_count: u64,
+    pub interruptible: bool,
+    pub interrupt_frame: Option<InterruptFrame>,
+    pub instruction_budget: Option<u64>,
+    pub deadline: Option<Instant>,
+    pub exit_reason: ExitReason,
+    pub instruction_start: u64,
 }
 
+// checking the host clock every instruction is too slow, so deadlines are only checked this often.
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
 impl VirtualThread {
-    pub fn new(vm: &mut VirtualMachine, from: u64, name: String) -> thread::JoinHandle<()> {
+    pub fn new(vm: &mut VirtualMachine, from: u64, name: String, interruptible: bool) -> thread::JoinHandle<ExitReason> {
         unsafe {
-            let ptr = Arc::from_raw(vm as *mut _);
+            // the machine is owned by the caller, so the thread must never drop it.
+            let ptr = ManuallyDrop::new(Arc::from_raw(vm as *mut VirtualMachine));
 
             thread::Builder::new().name(name).spawn(move || {
                 unsafe {
                     let mut registers = [0; 16];
                     registers[RegisterRoles::ProgramCounter as usize] = from;
+                    let tls = ptr.tls_template.clone();
+                    let (instruction_budget, deadline) = (ptr.instruction_budget, ptr.deadline);
                     let mut instance = Self { 
                         parent: ptr,
                         registers,
                         running: true,
                         alu_flags: 0,
                         stack: vec![],
+                        tls,
+                        instruction_count: 0,
+                        interruptible,
+                        interrupt_frame: None,
+                        instruction_budget,
+                        deadline,
+                        exit_reason: ExitReason::Halted(0),
+                        instruction_start: from,
                     };
                     instance.run();
-                };
+                    instance.exit_reason
+                }
             }).unwrap()
         }
     }
@@ -42,20 +67,98 @@ impl VirtualThread {
         self.registers[RegisterRoles::ProgramCounter as usize] += std::mem::size_of::<T>() as u64;
         ret
     }
+    // run checks an instruction's operands before its rule reads them, so this only fails when the host reads past the program.
     pub fn current<T>(&self) -> T {
+        let start = self.registers[RegisterRoles::ProgramCounter as usize] as usize;
+        let bytes = &self.parent.as_ref().instructions[start..start + std::mem::size_of::<T>()];
         unsafe {
-            read_unaligned((&self.parent.as_ref().instructions[0] as *const u8 as usize).wrapping_add(self.registers[RegisterRoles::ProgramCounter as usize] as usize) as *const T)
+            read_unaligned(bytes.as_ptr() as *const T)
+        }
+    }
+
+    // faults unless the operands after the opcode at pc fit in the program and every register operand names a register.
+    fn check_operands(&mut self, operands: &[Operand]) -> bool {
+        let instructions = &self.parent.as_ref().instructions;
+        let mut position = self.registers[RegisterRoles::ProgramCounter as usize] as usize;
+        for operand in operands {
+            let Some(bytes) = instructions.get(position..position + operand.size()) else {
+                self.fault(FaultKind::ProgramCounterOutOfBounds);
+                return false;
+            };
+            if *operand == Operand::Register && bytes[0] >= 16 {
+                self.fault(FaultKind::InvalidRegister(bytes[0] as u64));
+                return false;
+            }
+            position += operand.size();
+        }
+        true
+    }
+
+    // saves the interrupted state and jumps to the handler, which gets the line number in r0.
+    fn service_interrupt(&mut self) {
+        if let Some((line, handler)) = self.parent.interrupts.take() {
+            self.interrupt_frame = Some(InterruptFrame {
+                registers: self.registers,
+                alu_flags: self.alu_flags,
+            });
+            self.registers[0] = line as u64;
+            self.registers[RegisterRoles::ProgramCounter as usize] = handler;
+        }
+    }
+
+    pub fn stop(&mut self, reason: ExitReason) {
+        self.running = false;
+        self.exit_reason = reason;
+        crate::format::flush_guest_stdout();
+    }
+
+    // for syscalls that wait, stops the thread if the machine was cancelled or the deadline has passed.
+    pub fn should_stop(&mut self) -> bool {
+        if self.parent.cancelled.load(Ordering::Relaxed) {
+            self.stop(ExitReason::Cancelled);
+        } else if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            self.stop(ExitReason::DeadlineExceeded);
         }
+        !self.running
+    }
+
+    // stops the thread, blaming the instruction that is currently executing.
+    pub fn fault(&mut self, kind: FaultKind) {
+        self.stop(ExitReason::Fault(kind, self.instruction_start));
     }
 
     pub fn run(&mut self) {
         while self.running {
-            unsafe {
-                assert!(!(self.parent.as_ref().instructions.len() < self.registers[RegisterRoles::ProgramCounter as usize] as usize), "ran out of instructions at index {} of array of length {}", self.registers[RegisterRoles::ProgramCounter as usize], self.parent.as_ref().instructions.len());
-                let instruction = self.last::<u16>();
-                assert!(instruction < arsenal_globals::Instructions::__END__ as u16, "unidentified instruction id {} at {}", instruction, self.registers[RegisterRoles::ProgramCounter as usize]);
-                self.parent.as_ref().rules[instruction as usize](self);
+            if self.parent.cancelled.load(Ordering::Relaxed) {
+                self.stop(ExitReason::Cancelled);
+                break;
+            }
+            if self.instruction_budget.is_some_and(|budget| self.instruction_count >= budget) {
+                self.stop(ExitReason::BudgetExhausted);
+                break;
+            }
+            if self.instruction_count.is_multiple_of(DEADLINE_CHECK_INTERVAL) && self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                self.stop(ExitReason::DeadlineExceeded);
+                break;
+            }
+            if self.interruptible && self.interrupt_frame.is_none() {
+                self.service_interrupt();
+            }
+            self.instruction_start = self.registers[RegisterRoles::ProgramCounter as usize];
+            if self.parent.as_ref().instructions.len() < (self.instruction_start as usize).saturating_add(std::mem::size_of::<u16>()) {
+                self.fault(FaultKind::ProgramCounterOutOfBounds);
+                break;
+            }
+            let instruction = self.last::<u16>();
+            let Some(&operands) = self.parent.as_ref().operands.get(instruction as usize) else {
+                self.fault(FaultKind::InvalidInstruction(instruction));
+                break;
+            };
+            if !self.check_operands(operands) {
+                break;
             }
+            self.parent.as_ref().rules[instruction as usize](self);
+            self.instruction_count += 1;
         }
     }
 }
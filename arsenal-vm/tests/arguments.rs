@@ -0,0 +1,57 @@
+// the results each program keeps in its data section are read back from fixed offsets after the leading JumpTo.
+mod common;
+use common::{long_at, machine, string_at};
+
+#[test]
+fn guest_reads_its_arguments_and_environment() {
+    std::env::set_var("ARSENAL_TEST_GREETING", "hi there");
+    let mut vm = machine(r#"
+JumpTo &_start;
+label _data:
+    () count = #0;
+    () length = #0;
+    () missing = #0;
+    () too_long = #0;
+    () value_length = #0;
+    () unset = #0;
+    () argument = #0 #0 #0 #0;
+    () value = #0 #0 #0 #0;
+    () name = "ARSENAL_TEST_GREETING" 0;
+    () unset_name = "ARSENAL_TEST_UNSET" 0;
+label _start:
+    SysCall ArgCount;
+    MoveRegisterMemoryLong 0 &count;
+    LoadRegisterLong 0 #1;
+    LoadRegisterLong 1 &argument;
+    LoadRegisterLong 2 #32;
+    SysCall ArgGet;
+    MoveRegisterMemoryLong 0 &length;
+    LoadRegisterLong 0 #2;
+    LoadRegisterLong 2 #32;
+    SysCall ArgGet;
+    MoveRegisterMemoryLong 0 &missing;
+    LoadRegisterLong 0 #1;
+    LoadRegisterLong 2 #5;
+    SysCall ArgGet;
+    MoveRegisterMemoryLong 0 &too_long;
+    LoadRegisterLong 0 &name;
+    LoadRegisterLong 1 &value;
+    LoadRegisterLong 2 #32;
+    SysCall EnvGet;
+    MoveRegisterMemoryLong 0 &value_length;
+    LoadRegisterLong 0 &unset_name;
+    SysCall EnvGet;
+    MoveRegisterMemoryLong 0 &unset;
+    Halt;
+"#);
+    vm.set_arguments(vec!["program.ars".to_string(), "hello".to_string()]);
+    vm.run();
+    assert_eq!(long_at(&vm, 10), 2);
+    assert_eq!(long_at(&vm, 18), 5);
+    assert_eq!(long_at(&vm, 26), u64::MAX);
+    assert_eq!(long_at(&vm, 34), u64::MAX);
+    assert_eq!(long_at(&vm, 42), 8);
+    assert_eq!(long_at(&vm, 50), u64::MAX);
+    assert_eq!(string_at(&vm, 58), "hello");
+    assert_eq!(string_at(&vm, 90), "hi there");
+}
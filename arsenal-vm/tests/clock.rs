@@ -0,0 +1,36 @@
+// under the virtual clock a sleep advances time by exactly what was asked and returns at once.
+use std::time::{Duration, Instant};
+
+mod common;
+use common::{long_at, machine};
+
+#[test]
+fn sleep_advances_the_virtual_clock() {
+    let mut vm = machine(r#"
+JumpTo &_start;
+label _data:
+    () before = #0;
+    () after = #0;
+    () count = #0;
+label _start:
+    SysCall ClockMonotonic;
+    MoveRegisterMemoryLong 0 &before;
+    LoadRegisterLong 0 #1500;
+    SysCall Sleep;
+    SysCall ClockMonotonic;
+    MoveRegisterMemoryLong 0 &after;
+    SysCall CpuInstructionCount;
+    MoveRegisterMemoryLong 0 &count;
+    Halt;
+"#);
+    let clock = vm.use_virtual_clock();
+    clock.set(5);
+    let start = Instant::now();
+    vm.run();
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(long_at(&vm, 10), 5);
+    assert_eq!(long_at(&vm, 18), 5 + 1_500_000_000);
+    assert_eq!(clock.now(), 5 + 1_500_000_000);
+    // the JumpTo and the six instructions before the syscall.
+    assert_eq!(long_at(&vm, 26), 7);
+}
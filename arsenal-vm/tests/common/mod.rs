@@ -0,0 +1,43 @@
+// shared by the integration tests, not every test file uses all of it.
+#![allow(dead_code)]
+
+use std::time::{Duration, Instant};
+
+use arsenal_linker::{extract_host_syscalls, extract_instructions, extract_thread_local};
+use arsenal_vm::virtual_machine::{ExitReason, VirtualMachine};
+
+// assembles source into a machine with an instruction budget, so a broken test program cannot hang the suite.
+pub fn machine(source: &str) -> VirtualMachine {
+    let mut object = arsenal_assembler::new_parse("test.ars", source.as_bytes().to_vec()).expect("test program should assemble");
+    let mut vm = VirtualMachine::new(extract_instructions(&mut object), env!("CARGO_MANIFEST_DIR").to_string());
+    vm.load_thread_local(extract_thread_local(&mut object));
+    vm.load_host_syscalls(extract_host_syscalls(&mut object));
+    vm.set_instruction_budget(Some(100_000));
+    vm
+}
+
+pub fn run(source: &str, deadline: Option<Duration>) -> ExitReason {
+    let mut vm = machine(source);
+    vm.set_deadline(deadline.map(|deadline| Instant::now() + deadline));
+    vm.run()
+}
+
+// reads a long the guest stored in its own program, data labels right after a leading JumpTo start at 10.
+pub fn long_at(vm: &VirtualMachine, address: usize) -> u64 {
+    u64::from_le_bytes(vm.instructions[address..address + 8].try_into().unwrap())
+}
+
+// reads a null-terminated string the guest left in its own program.
+pub fn string_at(vm: &VirtualMachine, address: usize) -> String {
+    let bytes = &vm.instructions[address..];
+    let length = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..length]).into_owned()
+}
+
+// a fresh folder per test, tests run in parallel.
+pub fn scratch(name: &str) -> std::path::PathBuf {
+    let directory = std::env::temp_dir().join(format!("arsenal-vm-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    directory
+}
@@ -0,0 +1,59 @@
+// the results each program keeps in its data section are read back from fixed offsets after the leading JumpTo.
+use arsenal_globals::FileKinds;
+
+mod common;
+use common::{long_at, machine, scratch};
+
+#[test]
+fn directories_list_and_change() {
+    let directory = scratch("directories");
+    std::fs::write(directory.join("a.txt"), "12345").unwrap();
+    std::fs::create_dir(directory.join("sub")).unwrap();
+    let mut vm = machine(&format!(r#"
+JumpTo &_start;
+label _data:
+    () stat = #0 #0 #0;
+    () made = #0;
+    () removed = #0;
+    () names = #0 #0 #0 #0 #0 #0 #0 #0;
+    () directory = "{0}" 0;
+    () file = "{0}/a.txt" 0;
+    () new_directory = "{0}/made" 0;
+label _start:
+    LoadRegisterLong 0 &directory;
+    SysCall DirOpen;
+    LoadRegisterLong 5 &names;
+label next:
+    MoveRegistersLong 0x51;
+    LoadRegisterLong 2 #32;
+    SysCall DirRead;
+    CompareRegisterLiteralLong 1 #-1;
+    JumpIfEqualTo &done;
+    AddRegistersLong 0x15;
+    AddRegisterImmediateLong 5 #1;
+    JumpTo &next;
+label done:
+    SysCall DirClose;
+    LoadRegisterLong 0 &file;
+    LoadRegisterLong 1 &stat;
+    SysCall FileStat;
+    LoadRegisterLong 0 &new_directory;
+    SysCall MakeDirectory;
+    MoveRegisterMemoryLong 1 &made;
+    LoadRegisterLong 0 &file;
+    SysCall FileRemove;
+    MoveRegisterMemoryLong 1 &removed;
+    Halt;
+"#, directory.display()));
+    vm.run();
+
+    let mut names: Vec<_> = vm.instructions[50..114].split(|&byte| byte == 0).filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8_lossy(name).into_owned()).collect();
+    names.sort();
+    assert_eq!(names, ["a.txt", "sub"]);
+    assert_eq!(long_at(&vm, 10), 5);
+    assert_eq!(long_at(&vm, 26), FileKinds::File as u64);
+    assert_eq!((long_at(&vm, 34), long_at(&vm, 42)), (0, 0));
+    assert!(directory.join("made").is_dir());
+    assert!(!directory.join("a.txt").exists());
+}
@@ -0,0 +1,87 @@
+// programs that used to crash or corrupt the host, each one has to stop with a fault instead.
+use arsenal_globals::{Instructions, SysCalls};
+use arsenal_vm::virtual_machine::{ExitReason, FaultKind, VirtualMachine};
+
+mod common;
+use common::run;
+
+// for encodings the assembler refuses to produce.
+fn run_bytes(mut program: Vec<u8>) -> ExitReason {
+    let mut vm = VirtualMachine::new(&mut program, env!("CARGO_MANIFEST_DIR").to_string());
+    vm.set_instruction_budget(Some(100_000));
+    vm.run()
+}
+
+fn opcode(instruction: Instructions) -> [u8; 2] {
+    (instruction as u16).to_le_bytes()
+}
+
+#[test]
+fn syscall_register_argument_out_of_range_faults() {
+    let reason = run(r#"
+LoadRegisterLong 0 #99;
+SysCall PrintRegister;
+Halt;
+"#, None);
+    assert_eq!(reason, ExitReason::Fault(FaultKind::InvalidRegister(99), 11));
+}
+
+#[test]
+fn register_operand_out_of_range_faults() {
+    let program = [opcode(Instructions::CompareRegisterLiteralByte).as_slice(), &[0x20, 1]].concat();
+    assert_eq!(run_bytes(program), ExitReason::Fault(FaultKind::InvalidRegister(0x20), 0));
+}
+
+#[test]
+fn operands_past_the_end_of_the_program_fault() {
+    let program = [opcode(Instructions::LoadRegisterLong).as_slice(), &[0, 1, 2]].concat();
+    assert_eq!(run_bytes(program), ExitReason::Fault(FaultKind::ProgramCounterOutOfBounds, 0));
+}
+
+#[test]
+fn memory_outside_the_program_faults() {
+    let address = 1u64 << 40;
+    let program = [opcode(Instructions::MoveMemoryRegisterLong).as_slice(), &address.to_le_bytes(), &[0]].concat();
+    assert_eq!(run_bytes(program), ExitReason::Fault(FaultKind::InvalidAddress(address), 0));
+
+    let reason = run(r#"
+LoadRegisterLong 1 #4294967295;
+MoveRegisterAddressedRegisterLong 0x01;
+Halt;
+"#, None);
+    assert_eq!(reason, ExitReason::Fault(FaultKind::InvalidAddress(4294967295), 11));
+}
+
+#[test]
+fn allocated_memory_is_usable_until_freed() {
+    let program = |after_free: &str| format!(r#"
+LoadRegisterLong 0 #16;
+SysCall MemoryAllocate;
+MoveRegistersLong 0x05;
+LoadRegisterLong 1 #42;
+MoveRegisterAddressedRegisterLong 0x15;
+MoveAddressedRegisterRegisterLong 0x52;
+SysCall MemoryFree;
+{after_free}
+HaltRegister 2;
+"#);
+    assert_eq!(run(&program(""), None), ExitReason::Halted(42));
+    assert!(matches!(run(&program("MoveRegisterAddressedRegisterLong 0x15;"), None), ExitReason::Fault(FaultKind::InvalidAddress(_), _)));
+    assert!(matches!(run(&program("SysCall MemoryFree;"), None), ExitReason::Fault(FaultKind::InvalidAddress(_), _)));
+}
+
+#[test]
+fn popping_an_empty_stack_faults() {
+    let program = [opcode(Instructions::PopRegisterLong).as_slice(), &[0]].concat();
+    assert_eq!(run_bytes(program), ExitReason::Fault(FaultKind::StackPointerOutOfBounds(0), 0));
+}
+
+#[test]
+fn print_string_outside_memory_faults() {
+    let address = 1u64 << 40;
+    let program = [
+        opcode(Instructions::LoadRegisterLong).as_slice(), &[0], &address.to_le_bytes(),
+        &opcode(Instructions::SysCall), &[SysCalls::PrintCString as u8],
+    ].concat();
+    assert_eq!(run_bytes(program), ExitReason::Fault(FaultKind::InvalidAddress(address), 11));
+}
@@ -0,0 +1,148 @@
+// the results each program keeps in its data section are read back from fixed offsets after the leading JumpTo.
+use arsenal_globals::ErrorCodes;
+
+mod common;
+use common::{long_at, machine, scratch, string_at};
+
+#[test]
+fn written_file_reads_back() {
+    let path = scratch("read_back").join("file.txt");
+    let mut vm = machine(&format!(r#"
+JumpTo &_start;
+label _data:
+    () count = #0;
+    () eof = #0;
+    () buffer = #0 #0 #0 #0;
+    () path = "{}" 0;
+    () write_mode = "w" 0;
+    () read_mode = "r" 0;
+    (text_length) text = "hello file";
+label _start:
+    LoadRegisterLong 0 &path;
+    LoadRegisterLong 1 &write_mode;
+    SysCall FOpen;
+    LoadRegisterLong 1 &text;
+    LoadRegisterLong 2 $text_length;
+    SysCall FWrite;
+    SysCall FClose;
+    LoadRegisterLong 0 &path;
+    LoadRegisterLong 1 &read_mode;
+    SysCall FOpen;
+    LoadRegisterLong 1 &buffer;
+    LoadRegisterLong 2 #32;
+    SysCall FRead;
+    MoveRegisterMemoryLong 1 &count;
+    SysCall FEof;
+    MoveRegisterMemoryLong 1 &eof;
+    SysCall FClose;
+    Halt;
+"#, path.display()));
+    vm.run();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "hello file");
+    assert_eq!(long_at(&vm, 10), 10);
+    assert_eq!(long_at(&vm, 18), 1);
+    assert_eq!(string_at(&vm, 26), "hello file");
+}
+
+#[test]
+fn closed_and_unknown_handles_fail() {
+    let mut vm = machine(r#"
+JumpTo &_start;
+label _data:
+    () closed_getc = #0;
+    () closed_error = #0;
+    () unknown_write = #0;
+    () path = "Cargo.toml" 0;
+    () read_mode = "r" 0;
+label _start:
+    LoadRegisterLong 0 &path;
+    LoadRegisterLong 1 &read_mode;
+    SysCall FOpen;
+    SysCall FClose;
+    SysCall FGetC;
+    MoveRegisterMemoryLong 1 &closed_getc;
+    SysCall FError;
+    MoveRegisterMemoryLong 1 &closed_error;
+    LoadRegisterLong 0 #1234;
+    LoadRegisterLong 1 &path;
+    LoadRegisterLong 2 #1;
+    SysCall FWrite;
+    MoveRegisterMemoryLong 1 &unknown_write;
+    Halt;
+"#);
+    vm.run();
+    assert_eq!(long_at(&vm, 10), u64::MAX);
+    assert_eq!(long_at(&vm, 18), u64::MAX);
+    assert_eq!(long_at(&vm, 26), u64::MAX);
+}
+
+#[test]
+fn errors_are_kept_per_handle() {
+    let mut vm = machine(r#"
+JumpTo &_start;
+label _data:
+    () failed_error = #0;
+    () other_error = #0;
+    () path = "Cargo.toml" 0;
+    () read_mode = "r" 0;
+label _start:
+    LoadRegisterLong 0 &path;
+    LoadRegisterLong 1 &read_mode;
+    SysCall FOpen;
+    MoveRegistersLong 0x0a;
+    LoadRegisterLong 0 &path;
+    SysCall FOpen;
+    MoveRegistersLong 0x0b;
+    MoveRegistersLong 0xa0;
+    LoadRegisterLong 1 &path;
+    LoadRegisterLong 2 #4;
+    SysCall FWrite;
+    SysCall FError;
+    MoveRegisterMemoryLong 1 &failed_error;
+    MoveRegistersLong 0xb0;
+    SysCall FError;
+    MoveRegisterMemoryLong 1 &other_error;
+    Halt;
+"#);
+    vm.run();
+    let failed = long_at(&vm, 10);
+    assert!(failed != ErrorCodes::Success as u64 && failed != u64::MAX);
+    assert_eq!(long_at(&vm, 18), ErrorCodes::Success as u64);
+}
+
+#[test]
+fn standard_handles_are_open() {
+    let mut vm = machine(r#"
+JumpTo &_start;
+label _data:
+    () stdin_error = #0;
+    () stdout_flush = #0;
+    () stderr_written = #0;
+    () first_file = #0;
+    () path = "Cargo.toml" 0;
+    () read_mode = "r" 0;
+label _start:
+    LoadRegisterLong 0 #0;
+    SysCall FError;
+    MoveRegisterMemoryLong 1 &stdin_error;
+    LoadRegisterLong 0 #1;
+    SysCall FFlush;
+    MoveRegisterMemoryLong 1 &stdout_flush;
+    LoadRegisterLong 0 #2;
+    LoadRegisterLong 1 &path;
+    LoadRegisterLong 2 #0;
+    SysCall FWrite;
+    MoveRegisterMemoryLong 1 &stderr_written;
+    LoadRegisterLong 0 &path;
+    LoadRegisterLong 1 &read_mode;
+    SysCall FOpen;
+    MoveRegisterMemoryLong 0 &first_file;
+    Halt;
+"#);
+    vm.run();
+    assert_eq!(long_at(&vm, 10), 0);
+    assert_eq!(long_at(&vm, 18), 0);
+    assert_eq!(long_at(&vm, 26), 0);
+    // 0, 1 and 2 are taken by stdin, stdout and stderr.
+    assert_eq!(long_at(&vm, 34), 3);
+}
@@ -0,0 +1,45 @@
+// host syscalls get ids counting down from 255, the first one a program declares is 255.
+use std::sync::{Arc, Mutex};
+
+use arsenal_vm::permissions::{Permissions, SysCallGroup};
+use arsenal_vm::virtual_machine::{ExitReason, FaultKind};
+
+mod common;
+use common::machine;
+
+const RECORD: &str = r#"
+.syscall record;
+LoadRegisterLong 0 #7;
+SysCall record;
+LoadRegisterLong 0 #9;
+SysCall record;
+HaltRegister 0;
+"#;
+
+#[test]
+fn guest_reaches_a_registered_syscall_by_name() {
+    let seen = Arc::new(Mutex::new(vec![]));
+    let mut vm = machine(RECORD);
+    let record = seen.clone();
+    vm.register_syscall("record", None, move |thread| {
+        record.lock().unwrap().push(thread.registers[0]);
+        thread.registers[0] += 1;
+    });
+    assert_eq!(vm.run(), ExitReason::Halted(10));
+    assert_eq!(*seen.lock().unwrap(), [7, 9]);
+}
+
+#[test]
+fn unregistered_syscalls_fault() {
+    assert_eq!(machine(RECORD).run(), ExitReason::Fault(FaultKind::InvalidSysCall(255), 11));
+}
+
+#[test]
+fn host_syscalls_follow_their_group() {
+    let mut vm = machine(RECORD);
+    vm.register_syscall("record", Some(SysCallGroup::Network), |_| {});
+    let mut permissions = Permissions::default();
+    permissions.deny(SysCallGroup::Network);
+    vm.set_permissions(permissions);
+    assert_eq!(vm.run(), ExitReason::Fault(FaultKind::PermissionDenied(255), 11));
+}
@@ -0,0 +1,49 @@
+// the line is raised before the program starts, it stays pending until the guest registers a handler.
+mod common;
+use common::{long_at, machine};
+
+const PROGRAM: &str = r#"
+JumpTo &_start;
+label _data:
+    () line = #0;
+    () status = #0;
+    () kept = #0;
+    () handled = #0;
+label handler:
+    MoveRegisterMemoryLong 0 &line;
+    LoadRegisterLong 5 #99;
+    LoadRegisterLong 0 #99;
+    MoveRegisterMemoryLong 5 &handled;
+    InterruptReturn;
+label _start:
+    LoadRegisterLong 5 #7;
+    LoadRegisterLong 0 #3;
+    LoadRegisterLong 1 &handler;
+    SysCall InterruptRegister;
+    MoveRegisterMemoryLong 0 &status;
+    MoveRegisterMemoryLong 5 &kept;
+    Halt;
+"#;
+
+#[test]
+fn interrupt_runs_the_handler_and_resumes() {
+    let mut vm = machine(PROGRAM);
+    vm.handle().raise_interrupt(3);
+    vm.run();
+    // the handler got the line and ran once.
+    assert_eq!(long_at(&vm, 10), 3);
+    assert_eq!(long_at(&vm, 34), 99);
+    // the registers it clobbered were restored and the program went on after the syscall.
+    assert_eq!(long_at(&vm, 18), 0);
+    assert_eq!(long_at(&vm, 26), 7);
+}
+
+#[test]
+fn handler_only_runs_when_raised() {
+    let mut vm = machine(PROGRAM);
+    vm.run();
+    assert_eq!(long_at(&vm, 34), 0);
+    // r0 held the line number before the syscall, it reports success with 0.
+    assert_eq!(long_at(&vm, 18), 0);
+    assert_eq!(long_at(&vm, 26), 7);
+}
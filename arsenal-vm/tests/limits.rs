@@ -0,0 +1,68 @@
+// a guest that never halts must still be stopped by its budget, its deadline or the host.
+use std::thread;
+use std::time::{Duration, Instant};
+
+use arsenal_vm::virtual_machine::ExitReason;
+
+mod common;
+use common::{machine, run};
+
+const SPIN: &str = r#"
+label spin:
+    JumpTo &spin;
+"#;
+
+const SLEEP: &str = r#"
+LoadRegisterLong 0 #60000;
+SysCall Sleep;
+Halt;
+"#;
+
+#[test]
+fn budget_stops_a_loop() {
+    let mut vm = machine(SPIN);
+    vm.set_instruction_budget(Some(1000));
+    assert_eq!(vm.run(), ExitReason::BudgetExhausted);
+}
+
+#[test]
+fn deadline_stops_a_loop() {
+    let mut vm = machine(SPIN);
+    vm.set_instruction_budget(None);
+    vm.set_deadline(Some(Instant::now() + Duration::from_millis(100)));
+    assert_eq!(vm.run(), ExitReason::DeadlineExceeded);
+}
+
+#[test]
+fn cancel_stops_a_loop() {
+    let mut vm = machine(SPIN);
+    vm.set_instruction_budget(None);
+    let handle = vm.handle();
+    let host = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        handle.cancel();
+    });
+    assert_eq!(vm.run(), ExitReason::Cancelled);
+    host.join().unwrap();
+}
+
+#[test]
+fn deadline_stops_a_sleep() {
+    let start = Instant::now();
+    assert_eq!(run(SLEEP, Some(Duration::from_millis(100))), ExitReason::DeadlineExceeded);
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn cancel_stops_a_sleep() {
+    let mut vm = machine(SLEEP);
+    let handle = vm.handle();
+    let host = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        handle.cancel();
+    });
+    let start = Instant::now();
+    assert_eq!(vm.run(), ExitReason::Cancelled);
+    assert!(start.elapsed() < Duration::from_secs(10));
+    host.join().unwrap();
+}
@@ -0,0 +1,73 @@
+// the guest is limited to the crate's folder, which is also the folder the machine runs from.
+use std::path::Path;
+
+use arsenal_globals::SysCalls;
+use arsenal_vm::permissions::{Permissions, SysCallGroup};
+use arsenal_vm::virtual_machine::{ExitReason, FaultKind};
+
+mod common;
+use common::machine;
+
+fn limited_to_crate() -> Permissions {
+    let mut permissions = Permissions::default();
+    permissions.allow_filesystem(Path::new(env!("CARGO_MANIFEST_DIR")));
+    permissions
+}
+
+// LoadRegisterLong is 11 bytes, so the syscall is the second instruction.
+fn call(syscall: SysCalls, permissions: Permissions) -> ExitReason {
+    let mut vm = machine(&format!(r#"
+LoadRegisterLong 0 #0;
+SysCall {syscall:?};
+Halt;
+"#));
+    vm.set_permissions(permissions);
+    vm.run()
+}
+
+#[test]
+fn allow_fs_rejects_ffi_and_spawning() {
+    for syscall in [SysCalls::LoadDLL, SysCalls::CallCFunction, SysCalls::ProcessSpawn] {
+        assert_eq!(call(syscall, limited_to_crate()), ExitReason::Fault(FaultKind::PermissionDenied(syscall as u8), 11));
+    }
+}
+
+#[test]
+fn allow_fs_keeps_groups_that_were_allowed_again() {
+    let mut permissions = limited_to_crate();
+    permissions.allow(SysCallGroup::Ffi);
+    assert!(!matches!(call(SysCalls::LoadDLL, permissions), ExitReason::Fault(FaultKind::PermissionDenied(_), _)));
+}
+
+#[test]
+fn denied_groups_fault() {
+    let mut permissions = Permissions::default();
+    permissions.deny(SysCallGroup::Environment);
+    assert_eq!(call(SysCalls::EnvGet, permissions), ExitReason::Fault(FaultKind::PermissionDenied(SysCalls::EnvGet as u8), 11));
+}
+
+#[test]
+fn paths_outside_the_allowed_directory_fault() {
+    let program = |path: &str| format!(r#"
+JumpTo &_start;
+label _data:
+    () path = "{path}" 0;
+    () mode = "r" 0;
+label _start:
+    LoadRegisterLong 0 &path;
+    LoadRegisterLong 1 &mode;
+    SysCall FOpen;
+    CompareRegisterLiteralLong 0 #-1;
+    JumpIfEqualTo &failed;
+    HaltImmediate 0;
+label failed:
+    HaltImmediate 1;
+"#);
+    let mut vm = machine(&program("Cargo.toml"));
+    vm.set_permissions(limited_to_crate());
+    assert_eq!(vm.run(), ExitReason::Halted(0));
+
+    let mut vm = machine(&program("../Cargo.toml"));
+    vm.set_permissions(limited_to_crate());
+    assert!(matches!(vm.run(), ExitReason::Fault(FaultKind::PermissionDenied(id), _) if id == SysCalls::FOpen as u8));
+}
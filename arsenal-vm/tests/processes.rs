@@ -0,0 +1,37 @@
+// these spawn /bin/sh, the guest builds the argv block out of labels in its data section.
+use std::time::{Duration, Instant};
+
+use arsenal_vm::virtual_machine::ExitReason;
+
+mod common;
+use common::run;
+
+fn shell(script: &str) -> String {
+    format!(r#"
+JumpTo &_start;
+label _data:
+    () shell = "/bin/sh" 0;
+    () flag = "-c" 0;
+    () script = "{script}" 0;
+    () argv = &shell &flag &script;
+label _start:
+    LoadRegisterLong 0 &argv;
+    LoadRegisterLong 1 #3;
+    LoadRegisterLong 2 #0;
+    SysCall ProcessSpawn;
+    SysCall ProcessWait;
+    HaltRegister 1;
+"#)
+}
+
+#[test]
+fn process_wait_returns_the_exit_code() {
+    assert_eq!(run(&shell("exit 3"), None), ExitReason::Halted(3));
+}
+
+#[test]
+fn deadline_stops_a_thread_waiting_for_a_process() {
+    let start = Instant::now();
+    assert_eq!(run(&shell("sleep 30"), Some(Duration::from_millis(200))), ExitReason::DeadlineExceeded);
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
@@ -0,0 +1,220 @@
+// these only ever talk to 127.0.0.1, the host side of every exchange lives in the test itself.
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use arsenal_vm::virtual_machine::ExitReason;
+
+mod common;
+use common::run;
+
+// a port nothing is listening on, the listener is dropped before the port is handed out.
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+#[test]
+fn tcp_client_exchanges_data_with_host() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let host = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = [0; 4];
+        stream.read_exact(&mut received).unwrap();
+        stream.write_all(b"pong").unwrap();
+        let mut echoed = [0; 4];
+        stream.read_exact(&mut echoed).unwrap();
+        (received, echoed)
+    });
+
+    let reason = run(&format!(r#"
+JumpTo &_start;
+label _data:
+    () address = "{address}" 0;
+    (message_length) message = "ping";
+    (buffer_length) buffer = #0;
+label _start:
+    LoadRegisterLong 0 &address;
+    LoadRegisterLong 1 #1000;
+    SysCall SocketTcpConnect;
+    MoveRegistersLong 0x0a;
+    LoadRegisterLong 1 &message;
+    LoadRegisterLong 2 $message_length;
+    SysCall SocketSend;
+    MoveRegistersLong 0xa0;
+    LoadRegisterLong 1 &buffer;
+    LoadRegisterLong 2 $buffer_length;
+    SysCall SocketRecv;
+    MoveRegistersLong 0x12;
+    LoadRegisterLong 1 &buffer;
+    SysCall SocketSend;
+    SysCall SocketClose;
+    HaltRegister 1;
+"#), None);
+
+    assert_eq!(reason, ExitReason::Halted(0));
+    assert_eq!(host.join().unwrap(), (*b"ping", *b"pong"));
+}
+
+#[test]
+fn tcp_listener_accepts_host_connection() {
+    let port = free_port();
+    let host = thread::spawn(move || {
+        let started = Instant::now();
+        let mut stream = loop {
+            match TcpStream::connect(("127.0.0.1", port)) {
+                Ok(stream) => break stream,
+                Err(_) if started.elapsed() < Duration::from_secs(5) => thread::sleep(Duration::from_millis(10)),
+                Err(error) => panic!("guest never listened: {error}"),
+            }
+        };
+        stream.write_all(b"echo").unwrap();
+        let mut echoed = [0; 4];
+        stream.read_exact(&mut echoed).unwrap();
+        echoed
+    });
+
+    let reason = run(&format!(r#"
+JumpTo &_start;
+label _data:
+    () address = "127.0.0.1:{port}" 0;
+    (buffer_length) buffer = #0;
+label _start:
+    LoadRegisterLong 0 &address;
+    SysCall SocketTcpListen;
+    MoveRegistersLong 0x0b;
+    SysCall SocketTcpAccept;
+    MoveRegistersLong 0x0a;
+    LoadRegisterLong 1 &buffer;
+    LoadRegisterLong 2 #4;
+    SysCall SocketRecv;
+    MoveRegistersLong 0x12;
+    LoadRegisterLong 1 &buffer;
+    SysCall SocketSend;
+    SysCall SocketClose;
+    MoveRegistersLong 0xb0;
+    SysCall SocketClose;
+    HaltRegister 1;
+"#), None);
+
+    assert_eq!(reason, ExitReason::Halted(0));
+    assert_eq!(&host.join().unwrap(), b"echo");
+}
+
+#[test]
+fn tcp_accept_gives_up_after_its_timeout() {
+    let port = free_port();
+    let start = Instant::now();
+    let reason = run(&format!(r#"
+JumpTo &_start;
+label _data:
+    () address = "127.0.0.1:{port}" 0;
+label _start:
+    LoadRegisterLong 0 &address;
+    SysCall SocketTcpListen;
+    LoadRegisterLong 1 #200;
+    SysCall SocketSetTimeout;
+    SysCall SocketTcpAccept;
+    HaltRegister 0;
+"#), None);
+
+    assert_eq!(reason, ExitReason::Halted(255));
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn udp_datagrams_round_trip_with_sender_address() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let address = socket.local_addr().unwrap();
+    let host = thread::spawn(move || {
+        let mut received = [0; 16];
+        let (length, sender) = socket.recv_from(&mut received).unwrap();
+        assert_eq!(&received[..length], b"ping");
+        socket.send_to(b"pong", sender).unwrap();
+        // the guest answers to whatever address RecvFrom reported.
+        let (length, _) = socket.recv_from(&mut received).unwrap();
+        received[..length].to_vec()
+    });
+
+    let reason = run(&format!(r#"
+JumpTo &_start;
+label _data:
+    () bind_address = "127.0.0.1:0" 0;
+    () host_address = "{address}" 0;
+    (message_length) message = "ping";
+    (buffer_length) buffer = #0;
+    (sender_length) sender = #0 #0 #0 #0 #0 #0;
+label _start:
+    LoadRegisterLong 0 &bind_address;
+    SysCall SocketUdpBind;
+    LoadRegisterLong 1 #5000;
+    SysCall SocketSetTimeout;
+    LoadRegisterLong 1 &message;
+    LoadRegisterLong 2 $message_length;
+    LoadRegisterLong 3 &host_address;
+    SysCall SocketSendTo;
+    LoadRegisterLong 1 &buffer;
+    LoadRegisterLong 2 $buffer_length;
+    LoadRegisterLong 3 &sender;
+    LoadRegisterLong 4 $sender_length;
+    SysCall SocketRecvFrom;
+    MoveRegistersLong 0x12;
+    LoadRegisterLong 1 &buffer;
+    SysCall SocketSendTo;
+    SysCall SocketClose;
+    HaltRegister 1;
+"#), None);
+
+    assert_eq!(reason, ExitReason::Halted(0));
+    assert_eq!(host.join().unwrap(), b"pong");
+}
+
+#[test]
+fn recv_times_out_when_host_stays_silent() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    // keeps the connection open without ever writing to it.
+    let host = thread::spawn(move || listener.accept().unwrap());
+
+    let started = Instant::now();
+    let reason = run(&format!(r#"
+JumpTo &_start;
+label _data:
+    () address = "{address}" 0;
+    (buffer_length) buffer = #0;
+label _start:
+    LoadRegisterLong 0 &address;
+    LoadRegisterLong 1 #0;
+    SysCall SocketTcpConnect;
+    LoadRegisterLong 1 #50;
+    SysCall SocketSetTimeout;
+    LoadRegisterLong 1 &buffer;
+    LoadRegisterLong 2 $buffer_length;
+    SysCall SocketRecv;
+    HaltRegister 1;
+"#), None);
+
+    assert_eq!(reason, ExitReason::Halted(0xff));
+    assert!(started.elapsed() < Duration::from_secs(5));
+    drop(host.join().unwrap());
+}
+
+#[test]
+fn connect_to_closed_port_fails() {
+    let port = free_port();
+    let reason = run(&format!(r#"
+JumpTo &_start;
+label _data:
+    () address = "127.0.0.1:{port}" 0;
+label _start:
+    LoadRegisterLong 0 &address;
+    LoadRegisterLong 1 #1000;
+    SysCall SocketTcpConnect;
+    HaltRegister 0;
+"#), None);
+
+    assert_eq!(reason, ExitReason::Halted(0xff));
+}
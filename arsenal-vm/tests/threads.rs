@@ -0,0 +1,46 @@
+// the main thread changes its tls value before the worker starts, the worker must still see the template's value.
+use arsenal_vm::virtual_machine::VirtualMachine;
+
+mod common;
+use common::{long_at, machine};
+
+const PROGRAM: &str = r#"
+JumpTo &_start;
+label _data:
+    () main_saw = #0;
+    () worker_saw = #0;
+    () worker_start = &worker;
+.tls;
+    () value = #7;
+.text;
+label _start:
+    SysCall ThreadLocalBase;
+    AddRegisterImmediateLong 0 &value;
+    LoadRegisterLong 1 #42;
+    MoveRegisterAddressedRegisterLong 0x10;
+    MoveAddressedRegisterRegisterLong 0x02;
+    MoveRegisterMemoryLong 2 &main_saw;
+    Halt;
+label worker:
+    SysCall ThreadLocalBase;
+    AddRegisterImmediateLong 0 &value;
+    MoveAddressedRegisterRegisterLong 0x02;
+    MoveRegisterMemoryLong 2 &worker_saw;
+    Halt;
+"#;
+
+fn join(vm: &mut VirtualMachine) {
+    for thread in vm.threads.drain(..) {
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+fn threads_get_their_own_copy_of_tls() {
+    let mut vm = machine(PROGRAM);
+    vm.run();
+    vm.spawn(long_at(&vm, 26));
+    join(&mut vm);
+    assert_eq!(long_at(&vm, 10), 42);
+    assert_eq!(long_at(&vm, 18), 7);
+}
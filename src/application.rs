@@ -6,34 +6,67 @@ pub enum AppAction {
     Null,
 }
 
+use arsenal_vm::permissions::{Permissions, SysCallGroup};
+
 #[derive(Debug)]
 pub struct AppState {
-    pub input_file: String,
+    // several .ars files are assembled together into one program, a .arc file is run on its own.
+    pub input_files: Vec<String>,
     pub output_file: String,
     pub action: AppAction,
     pub base: String,
+    pub deterministic: bool,
+    pub guest_args: Vec<String>,
+    pub permissions: Permissions,
+    // -D NAME=value constants for the assembler.
+    pub defines: Vec<(String, i128)>,
 }
 
 pub fn parse_args(args: Vec<String>) -> AppState {
-    let mut input = "in.ars".to_string();
+    let mut inputs: Vec<String> = vec![];
     let mut output = "out.arc".to_string();
     let mut action = AppAction::Null;
+    let mut deterministic = false;
+    let mut guest_args = vec![];
+    let mut permissions = Permissions::default();
+    let mut defines = vec![];
 
     let mut arg_iter = args[1..].iter().peekable();
 
     while let Some(arg) = arg_iter.next() {
-        if arg.starts_with("-") {
+        if arg == "--" {
+            // everything after -- belongs to the guest program.
+            guest_args.extend(arg_iter.by_ref().cloned());
+        }
+        else if arg.starts_with("-") {
             match arg.as_str() {
                 "-o" => {
                     output = arg_iter.next().expect("expected file after -o").to_string();
                     if let AppAction::Null = action { action = AppAction::CompileRun }
                 },
                 "-c" => { action = AppAction::CompileExecutable; },
+                "--deterministic" => { deterministic = true; },
+                "-D" => {
+                    let define = arg_iter.next().expect("expected NAME=value after -D");
+                    defines.push(parse_define(define));
+                },
+                _ if arg.starts_with("--allow-fs=") => {
+                    // resolved now, the machine changes into the program's folder before it runs.
+                    permissions.allow_filesystem(Path::new(&arg["--allow-fs=".len()..]));
+                },
+                _ if arg.starts_with("--allow-") => {
+                    let group = SysCallGroup::from_name(&arg["--allow-".len()..]).unwrap_or_else(|| panic!("unknown syscall group in {}", arg));
+                    permissions.allow(group);
+                },
+                _ if arg.starts_with("--deny-") => {
+                    let group = SysCallGroup::from_name(&arg["--deny-".len()..]).unwrap_or_else(|| panic!("unknown syscall group in {}", arg));
+                    permissions.deny(group);
+                },
                 _ => {},
             }
         }
         else {
-            input = arg.to_string();
+            inputs.push(arg.to_string());
             if let AppAction::Null = action {
                 if arg.ends_with(".ars") {
                     action = AppAction::CompileRun;
@@ -44,18 +77,43 @@ pub fn parse_args(args: Vec<String>) -> AppState {
         }
     }
 
+    if inputs.is_empty() {
+        inputs.push("in.ars".to_string());
+    }
+    // the first file names the program, the machine runs from its folder and sees it as argument 0.
+    let input = inputs[0].clone();
     let base = match get_folder_path(&input) {
         Some(s) => s,
         None => "".to_string(),
     };
 
-    let state = AppState {
-        input_file: input,
+    guest_args.insert(0, input);
+
+    AppState {
+        input_files: inputs,
         output_file: output,
-        action: action,
-        base: base,
+        action,
+        base,
+        deterministic,
+        guest_args,
+        permissions,
+        defines,
+    }
+}
+
+// NAME=value with a decimal or 0x hex value, a bare NAME is 1.
+fn parse_define(define: &str) -> (String, i128) {
+    let (name, value) = define.split_once('=').unwrap_or((define, "1"));
+    let is_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !is_name {
+        panic!("invalid constant name in -D {}", define);
+    }
+    let parsed = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => i128::from_str_radix(hex, 16),
+        None => value.parse(),
     };
-    state
+    let value = parsed.unwrap_or_else(|_| panic!("invalid value in -D {}", define));
+    (name.to_string(), value)
 }
 
 use std::path::Path;
@@ -75,7 +133,7 @@ fn get_folder_path(file_path: &str) -> Option<String> {
 
 use std::{panic, env};
 
-pub fn custom_panic_hook(info: &panic::PanicInfo) {
+pub fn custom_panic_hook(info: &panic::PanicHookInfo) {
     if cfg!(not(debug_assertions)) {
         // If this is a release build, print a custom error message without backtrace.
         let error_message = extract_error_message(info);
@@ -86,7 +144,7 @@ pub fn custom_panic_hook(info: &panic::PanicInfo) {
     }
 }
 
-fn extract_error_message(info: &panic::PanicInfo) -> String {
+fn extract_error_message(info: &panic::PanicHookInfo) -> String {
     if let Some(message) = info.payload().downcast_ref::<&str>() {
         // If the payload is a string reference, return it as the error message.
         message.to_string()
@@ -105,3 +163,33 @@ pub fn init_hook() {
         panic::set_hook(Box::new(custom_panic_hook));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_define_without_a_value_is_one() {
+        assert_eq!(parse_define("DEBUG"), ("DEBUG".to_string(), 1));
+    }
+
+    #[test]
+    fn define_values_are_decimal_or_hex() {
+        assert_eq!(parse_define("LEVEL=2"), ("LEVEL".to_string(), 2));
+        assert_eq!(parse_define("OFFSET=-16"), ("OFFSET".to_string(), -16));
+        assert_eq!(parse_define("MASK=0xff"), ("MASK".to_string(), 255));
+        assert_eq!(parse_define("_MASK2=0XFF"), ("_MASK2".to_string(), 255));
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid value in -D LEVEL=two")]
+    fn a_define_value_has_to_be_a_number() {
+        parse_define("LEVEL=two");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid constant name in -D 2LEVEL=1")]
+    fn a_define_name_has_to_be_a_name() {
+        parse_define("2LEVEL=1");
+    }
+}
@@ -2,11 +2,14 @@
 #![allow(non_snake_case)]
 extern crate arsenal_assembler;
 pub mod application;
-use arsenal_linker::{extract_instructions, encode, decode};
+use arsenal_linker::{extract_instructions, extract_thread_local, extract_host_syscalls, encode, decode};
+use arsenal_vm::virtual_machine::ExitReason;
+use arsenal_globals::ArsenalObject;
 
 use application::AppAction::*;
 
 use std::fs::{read, write};
+use std::io::Write;
 use std::env::args;
 use std::rc::Rc;
 
@@ -19,23 +22,65 @@ fn main() {
 
     match state.action {
         CompileRun => {
-            let data = read(&state.input_file).expect(&format!("Error opening file {}: no such file", state.input_file));
-            let mut result = arsenal_assembler::new_parse(data).expect(&format!("failed to parse {}", state.input_file));
+            let mut result = assemble(&state.input_files, &state.defines);
             let mut vm = arsenal_vm::virtual_machine::VirtualMachine::new(extract_instructions(&mut result), state.base);
-            vm.run();
+            vm.load_thread_local(extract_thread_local(&mut result));
+            vm.load_host_syscalls(extract_host_syscalls(&mut result));
+            vm.set_arguments(state.guest_args);
+            vm.set_permissions(state.permissions);
+            if state.deterministic { vm.use_virtual_clock(); }
+            let reason = vm.run();
+            // closes any handles the guest leaked before the process goes away.
+            drop(vm);
+            exit(reason);
         },
         CompileExecutable => {
-            let data = read(&state.input_file).expect(&format!("Error opening file {}: no such file", state.input_file));
-            let result = arsenal_assembler::new_parse(data).expect(&format!("failed to parse {}", state.input_file));
+            let result = assemble(&state.input_files, &state.defines);
             write(state.output_file, encode(&result));
         },
         Run => {
-            let mut data = read(&state.input_file).expect(&format!("Error opening file {}: no such file", state.input_file));
+            let [input_file] = state.input_files.as_slice() else { panic!("only one .arc file can be run at a time") };
+            let mut data = read(input_file).unwrap_or_else(|_| panic!("Error opening file {}: no such file", input_file));
             let mut data = decode(data);
             let mut vm = arsenal_vm::virtual_machine::VirtualMachine::new(extract_instructions(&mut data), state.base);
-            vm.run();
+            vm.load_thread_local(extract_thread_local(&mut data));
+            vm.load_host_syscalls(extract_host_syscalls(&mut data));
+            vm.set_arguments(state.guest_args);
+            vm.set_permissions(state.permissions);
+            if state.deterministic { vm.use_virtual_clock(); }
+            let reason = vm.run();
+            // closes any handles the guest leaked before the process goes away.
+            drop(vm);
+            exit(reason);
         },
         Null => panic!("input file required"),
     }
 
 }
+
+fn assemble(paths: &[String], defines: &[(String, i128)]) -> ArsenalObject {
+    let files = paths.iter().map(|path| {
+        let data = read(path).unwrap_or_else(|_| panic!("Error opening file {}: no such file", path));
+        (path.clone(), data)
+    }).collect();
+    arsenal_assembler::parse_files(files, defines).unwrap_or_else(|diagnostics| {
+        for diagnostic in &diagnostics {
+            eprintln!("{}\n", diagnostic);
+        }
+        let plural = if diagnostics.len() == 1 { "" } else { "s" };
+        eprintln!("could not assemble {} due to {} error{}", paths.join(", "), diagnostics.len(), plural);
+        std::process::exit(1);
+    })
+}
+
+fn exit(reason: ExitReason) -> ! {
+    match reason {
+        ExitReason::Fault(kind, pc) => eprintln!("fault {:?} at {}", kind, pc),
+        ExitReason::BudgetExhausted => eprintln!("instruction budget exhausted"),
+        ExitReason::DeadlineExceeded => eprintln!("deadline exceeded"),
+        ExitReason::Cancelled => eprintln!("cancelled"),
+        ExitReason::Halted(_) => {},
+    }
+    std::io::stdout().flush();
+    std::process::exit(reason.exit_code());
+}